@@ -0,0 +1,23 @@
+// Prints a listing of a flat binary, like a minimal `objdump -d`.
+//
+// cargo run --example disasm -- program.bin
+use riscv::disasm::disassemble;
+use std::{env, fs, io, process};
+
+fn main() {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("usage: disasm <binary>");
+        process::exit(1);
+    };
+    let program = match fs::read(&path) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("failed to read {path}: {err}");
+            process::exit(1);
+        }
+    };
+    if let Err(err) = disassemble(&program, &mut io::stdout().lock()) {
+        eprintln!("failed to write listing: {err}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,104 @@
+// Encoders for the handful of instructions needed to expand
+// the `li` and `la` pseudo-instructions.
+
+use crate::inst_format::{IFormat, UFormat};
+
+const OP_IMM: u32 = 0b0010011;
+const LUI: u32 = 0b0110111;
+const AUIPC: u32 = 0b0010111;
+
+// addi rd, rs1, imm (only imm[11:0] is encoded)
+pub fn addi(rd: usize, rs1: usize, imm: i32) -> u32 {
+    let format = IFormat {
+        rd,
+        funct3: 0x0,
+        rs1,
+        imm: imm as u32,
+    };
+    format.encode(OP_IMM)
+}
+
+// lui rd, imm (only imm[19:0] is encoded)
+pub fn lui(rd: usize, imm: u32) -> u32 {
+    UFormat { rd, imm }.encode(LUI)
+}
+
+// auipc rd, imm (only imm[19:0] is encoded)
+pub fn auipc(rd: usize, imm: u32) -> u32 {
+    UFormat { rd, imm }.encode(AUIPC)
+}
+
+// Splits a 32-bit value into the upper 20 and lower 12 bits
+// so that `(hi << 12) + lo == val`.
+// `addi` sign-extends its immediate, so when bit 11 of the value is set
+// the low part is negative and `hi` has to be one bigger to compensate:
+// 0x12800 = (0x13 << 12) + (-0x800)
+fn split(val: i32) -> (u32, i32) {
+    let hi = (val as u32).wrapping_add(0x800) >> 12;
+    let lo = val.wrapping_sub((hi << 12) as i32);
+    (hi, lo)
+}
+
+// li rd, imm
+// A single `addi` if the immediate fits in 12 bits, `lui` + `addi` otherwise.
+pub fn li(rd: usize, imm: i32) -> Vec<u32> {
+    if (-2048..2048).contains(&imm) {
+        return vec![addi(rd, 0, imm)];
+    }
+    match split(imm) {
+        (hi, 0) => vec![lui(rd, hi)],
+        (hi, lo) => vec![lui(rd, hi), addi(rd, rd, lo)],
+    }
+}
+
+// la rd, symbol
+// Loads the address `symbol` relative to `pc` (the address of the `auipc`).
+pub fn la(rd: usize, pc: u32, symbol: u32) -> [u32; 2] {
+    let (hi, lo) = split(symbol.wrapping_sub(pc) as i32);
+    [auipc(rd, hi), addi(rd, rd, lo)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn run(insts: &[u32]) -> Cpu {
+        let mut cpu = Cpu::new(false);
+        for &inst in insts {
+            cpu.execute_raw(inst).unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn li_small() {
+        // addi x1, x0, -1
+        assert_eq!(li(1, -1), [0xfff00093]);
+    }
+
+    #[test]
+    fn li_large() {
+        // lui x1, 0x12; addi x1, x1, 0x345
+        assert_eq!(li(1, 0x12345), [0x000120b7, 0x34508093]);
+    }
+
+    #[test]
+    fn li_negative_low_part() {
+        // lui x1, 0x13; addi x1, x1, -0x800
+        assert_eq!(li(1, 0x12800), [0x000130b7, 0x80008093]);
+        for imm in [0x12800, 0x7ffff800, -0x801, i32::MIN, i32::MAX, 0x1000] {
+            assert_eq!(run(&li(1, imm)).regs.read(1), imm as u32);
+        }
+    }
+
+    #[test]
+    fn la_relative() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(0x100);
+        for inst in la(5, 0x100, 0x1f00) {
+            cpu.execute_raw(inst).unwrap();
+        }
+        assert_eq!(cpu.regs.read(5), 0x1f00);
+    }
+}
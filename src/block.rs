@@ -0,0 +1,135 @@
+// Basic-block cache: straight-line runs of instructions compiled into
+// chains of closures over their decoded form, keyed by the entry PC.
+// Running a block skips fetching and decoding, see `Cpu::with_block_cache`.
+use crate::cpu::Cpu;
+use crate::error::Error;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Longest block, so whatever is checked between blocks
+// (e.g. the stop signal) still runs regularly.
+pub const MAX_BLOCK_LEN: usize = 64;
+
+// Code is tracked in lines of this many bytes,
+// a store to a line holding cached code drops every block.
+const LINE_SIZE: u32 = 64;
+
+// Executes one pre-decoded instruction, the PC already points past it.
+pub type BlockOp = Box<dyn Fn(&mut Cpu) -> Result<(), Error>>;
+
+#[derive(Default)]
+pub struct Block {
+    // length of each instruction in bytes and its closure
+    pub ops: Vec<(u32, BlockOp)>,
+}
+
+impl Block {
+    // Bytes of code from the entry to the end of the last instruction.
+    pub fn size(&self) -> u32 {
+        self.ops.iter().map(|(len, _)| len).sum()
+    }
+}
+
+pub struct BlockCache {
+    // shared, so a block can run while it's being invalidated
+    blocks: HashMap<u32, Rc<Block>>,
+    // lines holding the code of a cached block
+    code_lines: Vec<bool>,
+    // bumped on every invalidation
+    generation: u64,
+    compiled: u64,
+}
+
+impl BlockCache {
+    // Tracks code in the first `mem_size` bytes,
+    // which is where instructions are fetched from.
+    pub fn new(mem_size: usize) -> Self {
+        BlockCache {
+            blocks: HashMap::new(),
+            code_lines: vec![false; mem_size.div_ceil(LINE_SIZE as usize)],
+            generation: 0,
+            compiled: 0,
+        }
+    }
+
+    pub fn get(&self, entry: u32) -> Option<Rc<Block>> {
+        self.blocks.get(&entry).cloned()
+    }
+
+    pub fn insert(&mut self, entry: u32, block: Block) -> Rc<Block> {
+        let end = entry.saturating_add(block.size());
+        for line in entry / LINE_SIZE..end.div_ceil(LINE_SIZE) {
+            if let Some(code) = self.code_lines.get_mut(line as usize) {
+                *code = true;
+            }
+        }
+        self.compiled += 1;
+        let block = Rc::new(block);
+        self.blocks.insert(entry, block.clone());
+        block
+    }
+
+    // Called on every store, drops all blocks if it overwrote cached code.
+    pub fn invalidate(&mut self, addr: u32, size: u32) {
+        let end = addr.saturating_add(size);
+        let lines = addr / LINE_SIZE..end.div_ceil(LINE_SIZE);
+        if lines.into_iter().any(|line| self.code_lines.get(line as usize) == Some(&true)) {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.code_lines.fill(false);
+        self.generation += 1;
+    }
+
+    // Changes whenever blocks are dropped, a running block
+    // stops when it does, as its next instructions may be stale.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Blocks compiled so far, including ones compiled again after a flush.
+    pub fn compiled(&self) -> u64 {
+        self.compiled
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Block {
+        let ops = (0..len).map(|_| (4, Box::new(|_: &mut Cpu| Ok(())) as BlockOp)).collect();
+        Block { ops }
+    }
+
+    #[test]
+    fn invalidate_code_lines() {
+        let mut cache = BlockCache::new(0x1000);
+        cache.insert(0x100, block(4));
+        assert_eq!(cache.get(0x100).unwrap().size(), 16);
+        // a store to another line keeps it
+        cache.invalidate(0x200, 4);
+        assert_eq!((cache.len(), cache.generation()), (1, 0));
+        // past the end of memory
+        cache.invalidate(0xfffffffe, 4);
+        assert_eq!(cache.len(), 1);
+        cache.invalidate(0x10c, 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.generation(), 1);
+        // the line isn't code anymore
+        cache.insert(0x200, block(1));
+        cache.invalidate(0x100, 4);
+        assert_eq!((cache.len(), cache.compiled()), (1, 2));
+    }
+}
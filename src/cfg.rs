@@ -0,0 +1,158 @@
+// Control-flow graph of a program, built by statically
+// decoding it without running anything.
+use crate::cpu::Cpu;
+use crate::inst::{IInst, Inst};
+use std::collections::BTreeSet;
+
+#[derive(Debug, PartialEq)]
+pub enum Successor {
+    // start address of the next block
+    Block(u32),
+    // indirect jump (jalr) or a target outside of the program
+    Unknown,
+}
+
+#[derive(Debug)]
+pub struct BasicBlock {
+    // address of the first instruction
+    pub start: u32,
+    // address right after the last instruction
+    pub end: u32,
+    pub successors: Vec<Successor>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    // sorted by start address
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn block(&self, start: u32) -> Option<&BasicBlock> {
+        self.blocks.iter().find(|block| block.start == start)
+    }
+}
+
+// Address an instruction at `addr` branches or jumps to, if it's static.
+// Unlike during execution the offset is relative to the instruction itself.
+fn target(addr: u32, inst: &Inst) -> Option<u32> {
+    match inst {
+        Inst::B(_, format) => Some(addr.wrapping_add(format.imm)),
+        Inst::J(format) => Some(addr.wrapping_add(format.imm)),
+        _ => None,
+    }
+}
+
+// Splits a program loaded at address 0 into basic blocks.
+// A block ends at a branch/jump, right before a branch/jump target,
+// at an instruction that fails to decode or at the end of the program.
+pub fn build_cfg(program: &[u8]) -> Cfg {
+    let insts = Cpu::new(false).decode_all(program);
+    let program_end = program.len() as u32;
+    let addrs: BTreeSet<u32> = insts.iter().map(|(addr, _)| *addr).collect();
+
+    // addresses starting a new block
+    let mut leaders = BTreeSet::from([0]);
+    for (i, (addr, inst)) in insts.iter().enumerate() {
+        let ends_block = match inst {
+            Ok(inst) => inst.is_control_flow(),
+            Err(_) => true,
+        };
+        if !ends_block {
+            continue;
+        }
+        if let Some((next, _)) = insts.get(i + 1) {
+            leaders.insert(*next);
+        }
+        if let Some(target) = inst.as_ref().ok().and_then(|inst| target(*addr, inst))
+            && addrs.contains(&target)
+        {
+            leaders.insert(target);
+        }
+    }
+
+    let successor = |addr: u32| {
+        if addrs.contains(&addr) {
+            Successor::Block(addr)
+        } else {
+            Successor::Unknown
+        }
+    };
+    let mut blocks = Vec::new();
+    for (i, (addr, inst)) in insts.iter().enumerate() {
+        let next = insts.get(i + 1).map(|(next, _)| *next);
+        // the last instruction of a block is followed by a leader or the end of the program
+        if next.is_some_and(|next| !leaders.contains(&next)) {
+            continue;
+        }
+        let start = *leaders.range(..=addr).next_back().unwrap();
+        let fall_through = next.map(Successor::Block);
+        let successors = match inst {
+            Err(_) => vec![],
+            Ok(Inst::I(IInst::Jalr, _)) => vec![Successor::Unknown],
+            Ok(inst @ Inst::J(_)) => vec![successor(target(*addr, inst).unwrap())],
+            Ok(inst @ Inst::B(..)) => {
+                let mut successors = vec![successor(target(*addr, inst).unwrap())];
+                successors.extend(fall_through);
+                successors
+            }
+            Ok(_) => fall_through.into_iter().collect(),
+        };
+        blocks.push(BasicBlock {
+            start,
+            end: next.unwrap_or(program_end),
+            successors,
+        });
+    }
+    Cfg { blocks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(insts: &[u32]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn simple_loop() {
+        let cfg = build_cfg(&program(&[
+            // addi x1, x0, 0
+            0x00000093,
+            // addi x2, x0, 10
+            0x00a00113,
+            // loop: addi x1, x1, 1
+            0x00108093,
+            // blt x1, x2, loop
+            0xfe20cee3,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]));
+        assert_eq!(cfg.blocks.len(), 3);
+        assert_eq!(cfg.block(0).unwrap().successors, [Successor::Block(8)]);
+        let body = cfg.block(8).unwrap();
+        assert_eq!(body.end, 16);
+        // back-edge and fall-through
+        assert_eq!(body.successors, [Successor::Block(8), Successor::Block(16)]);
+        // end of the program
+        assert!(cfg.block(16).unwrap().successors.is_empty());
+    }
+
+    #[test]
+    fn indirect_and_outside_jumps() {
+        let cfg = build_cfg(&program(&[
+            // jal x0, 8
+            0x0080006f,
+            // jalr x0, 0(x1)
+            0x00008067,
+            // jal x0, 0x100
+            0x1000006f,
+        ]));
+        assert_eq!(cfg.block(0).unwrap().successors, [Successor::Block(8)]);
+        assert_eq!(cfg.block(4).unwrap().successors, [Successor::Unknown]);
+        assert_eq!(cfg.block(8).unwrap().successors, [Successor::Unknown]);
+    }
+}
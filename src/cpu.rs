@@ -1,72 +1,1042 @@
+use crate::block::{Block, BlockCache, BlockOp, MAX_BLOCK_LEN};
+use crate::device::{Framebuffer, PixelFormat, TestFinisher};
+use crate::elf::{self, Elf};
 use crate::error::*;
 use crate::get_bits;
 use crate::inst::*;
 use crate::inst_format::*;
 use crate::memory::*;
+use crate::mmu::{self, Access, SATP, Tlb};
 use crate::pc::*;
+use crate::pipeline::{Pipeline, PipelineStats};
 use crate::regs::*;
+use crate::rng::XorShift;
+use crate::sandbox::{self, PolicyViolation, SandboxPolicy};
+use crate::symbols::SymbolMap;
+use crate::syscall::*;
+#[cfg(feature = "serde")]
+use crate::trace::{TraceAccess, TraceStep};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-enum ProgState {
+// Clock rate `clock_gettime` converts executed instructions to time with,
+// 1 GHz makes every instruction take a nanosecond.
+pub const DEFAULT_FREQUENCY: u64 = 1_000_000_000;
+
+// Zicntr counters, read-only. Every instruction takes a cycle,
+// so both count executed instructions.
+const CYCLE: u32 = 0xc00;
+const INSTRET: u32 = 0xc02;
+const CYCLEH: u32 = 0xc80;
+const INSTRETH: u32 = 0xc82;
+
+// result of executing a single instruction
+#[derive(Debug, PartialEq)]
+pub enum StepOutcome {
     Continue,
     Exit(u8),
+    // `ebreak` at the given address
+    Breakpoint(u32),
+    // a load or store touched a watched address
+    Watchpoint(u32),
+    // the host raised the stop signal, nothing was executed
+    Interrupted,
+}
+
+// Final machine state of a completed run, see `run_to_completion`.
+pub struct RunResult<'a> {
+    pub exit_code: u8,
+    pub regs: [u32; 32],
+    pub pc: u32,
+    // executed instructions, every instruction takes one cycle
+    pub cycles: u64,
+    pub mem: &'a Memory,
+}
+
+// One retired instruction of a reference trace (e.g. Spike's commit log):
+// its address and the register it wrote, if any.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommitRecord {
+    pub pc: u32,
+    pub write: Option<(usize, u32)>,
+}
+
+// first step where the emulator disagrees with the reference trace
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub expected: CommitRecord,
+    // the register from `expected` as it is after the step
+    pub actual: CommitRecord,
+}
+
+// `ecall` about to be executed, as seen by `continue_until_syscall`
+#[derive(Debug, PartialEq)]
+pub struct SyscallEntry {
+    pub pc: u32,
+    // a7
+    pub number: u32,
+    // a0-a5
+    pub args: [u32; 6],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyscallStop {
+    Entry(SyscallEntry),
+    // the program stopped for another reason before reaching a syscall
+    Done(StepOutcome),
+}
+
+// diagnostics about suspicious but legal program behavior
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    // signed overflow in ADD/ADDI/SUB at the given address
+    SignedOverflow(u32),
+}
+
+// Memory access or fence in program order, see `enable_memory_log`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemEvent {
+    Load { pc: u32, addr: u32, val: u32 },
+    Store { pc: u32, addr: u32, val: u32 },
+    // any kind of fence, including `fence.tso` and `pause`
+    Fence { pc: u32 },
 }
 
+// which memory accesses trigger a watchpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // either read or write
+    Access,
+}
+
+// callback fired when execution reaches an address
+pub type PcHook = Box<dyn FnMut(&mut Cpu)>;
+
+// State passed to the `on_cycle` callback before an instruction executes,
+// e.g. to animate execution in a UI.
+pub struct CycleSnapshot {
+    pub cycle: u64,
+    pub pc: u32,
+    pub inst: Inst,
+    // registers whose value differs from the previous snapshot, with the new value
+    pub changed: Vec<(usize, u32)>,
+}
+
+pub type CycleHook = Box<dyn FnMut(&CycleSnapshot)>;
+
+// Executes instructions from the custom-0..3 opcode space (accelerators, experiments).
+// Gets the raw instruction, returning `Error::InvalidOpcode` rejects it.
+pub type CustomDecoder = Box<dyn FnMut(&mut Cpu, u32) -> Result<(), Error>>;
+
 pub struct Cpu {
     pub pc: ProgramCounter,
     pub regs: Registers,
     pub mem: Memory,
     print_debug: bool,
+    misaligned_access: MisalignedAccess,
+    // addresses of instructions executed at least once
+    coverage: Option<HashSet<u32>>,
+    // last executed `(pc, raw_inst)` pairs, oldest first
+    history: Option<VecDeque<(u32, u32)>>,
+    history_size: usize,
+    // backs `getrandom`, seeded so runs are reproducible
+    rng: XorShift,
+    sandbox: SandboxPolicy,
+    finisher: Option<TestFinisher>,
+    framebuffer: Option<Framebuffer>,
+    // exit code requested by a device
+    exit_request: Option<u8>,
+    watchpoints: HashMap<u32, WatchKind>,
+    write_protected: Vec<Range<u32>>,
+    // mnemonics the program may execute, anything goes if `None`
+    allowed_insts: Option<HashSet<String>>,
+    // with the registers as of the last call
+    cycle_hook: Option<(CycleHook, [u32; 32])>,
+    pc_hooks: HashMap<u32, PcHook>,
+    custom_decoder: Option<CustomDecoder>,
+    stop_signal: Option<Arc<AtomicBool>>,
+    symbols: SymbolMap,
+    pipeline: Option<Pipeline>,
+    // executed instructions per mnemonic
+    histogram: Option<HashMap<String, u64>>,
+    // `ecall` that `continue_until_syscall` stopped right before
+    syscall_entry: Option<u32>,
+    // receives every executed raw instruction, see `record`
+    recorder: Option<Box<dyn Write>>,
+    // receives a JSON line per executed instruction, see `trace_json`
+    json_trace: Option<Box<dyn Write>>,
+    // last memory access of the current instruction, while tracing
+    traced_access: Option<MemEvent>,
+    // watched address touched by the current instruction
+    watch_hit: Option<u32>,
+    // address translation, see `mmu`
+    satp: u32,
+    tlb: Tlb,
+    // raise an error instead of returning spec values on division by zero
+    trap_div_by_zero: bool,
+    // decode Zbb bit-manipulation instructions
+    zbb: bool,
+    // lowest address the stack pointer may point to
+    stack_guard: Option<u32>,
+    // decoded instructions together with their raw encoding,
+    // indexed by halfword address, see `with_decode_cache`
+    decode_cache: Option<Vec<Option<(u32, Inst)>>>,
+    // compiled basic blocks, see `with_block_cache`
+    block_cache: Option<BlockCache>,
+    // accept 16-bit compressed (RVC) instructions
+    compressed: bool,
+    warn_on_overflow: bool,
+    warnings: Vec<Warning>,
+    memory_log: Option<Vec<MemEvent>>,
+    // number of executed instructions
+    cycles: u64,
+    // instructions per second, see `DEFAULT_FREQUENCY`
+    frequency: u64,
+    syscalls: u64,
+    stdout_bytes: u64,
 }
 
 impl Cpu {
     pub fn new(print_debug: bool) -> Self {
+        let mem = Memory::new();
         Cpu {
             pc: ProgramCounter::new(),
-            regs: Registers::new(),
-            mem: Memory::new(),
+            regs: Registers::new(mem.len() as u32),
+            mem,
             print_debug,
+            misaligned_access: MisalignedAccess::Emulate,
+            coverage: None,
+            history: None,
+            history_size: 0,
+            rng: XorShift::default(),
+            sandbox: SandboxPolicy::default(),
+            finisher: None,
+            framebuffer: None,
+            exit_request: None,
+            watchpoints: HashMap::new(),
+            write_protected: Vec::new(),
+            allowed_insts: None,
+            cycle_hook: None,
+            pc_hooks: HashMap::new(),
+            custom_decoder: None,
+            stop_signal: None,
+            symbols: SymbolMap::new(),
+            pipeline: None,
+            histogram: None,
+            recorder: None,
+            json_trace: None,
+            traced_access: None,
+            syscall_entry: None,
+            watch_hit: None,
+            satp: 0,
+            tlb: Tlb::new(),
+            trap_div_by_zero: false,
+            zbb: false,
+            compressed: true,
+            decode_cache: None,
+            block_cache: None,
+            stack_guard: None,
+            warn_on_overflow: false,
+            warnings: Vec::new(),
+            memory_log: None,
+            cycles: 0,
+            frequency: DEFAULT_FREQUENCY,
+            syscalls: 0,
+            stdout_bytes: 0,
+        }
+    }
+
+    // Debugging aid: stops on DIV/DIVU/REM/REMU with a zero divisor.
+    // RISC-V defines the result of division by zero,
+    // so by default it doesn't trap.
+    pub fn with_div_by_zero_trap(mut self) -> Self {
+        self.trap_div_by_zero = true;
+        self
+    }
+
+    pub fn traps_div_by_zero(&self) -> bool {
+        self.trap_div_by_zero
+    }
+
+    // Debugging aid: reports signed overflow in ADD/ADDI/SUB as warnings.
+    // Wrapping is the defined behavior, so it's off by default.
+    pub fn with_overflow_warnings(mut self) -> Self {
+        self.warn_on_overflow = true;
+        self
+    }
+
+    pub fn warns_on_overflow(&self) -> bool {
+        self.warn_on_overflow
+    }
+
+    // Records `warning`, printing it too in debug mode.
+    // Library users read them back with `warnings`.
+    pub fn warn(&mut self, warning: Warning) {
+        if self.print_debug {
+            eprintln!("warning: {warning:?}");
+        }
+        self.warnings.push(warning);
+    }
+
+    // Starts recording every load, store and fence, e.g. as input for a
+    // memory-model (litmus test) checker. Loads and stores of devices are included.
+    pub fn enable_memory_log(&mut self) {
+        self.memory_log.get_or_insert_with(Vec::new);
+    }
+
+    // Events recorded so far in program order, empty if logging isn't enabled.
+    pub fn memory_log(&self) -> &[MemEvent] {
+        self.memory_log.as_deref().unwrap_or_default()
+    }
+
+    fn log_memory(&mut self, event: MemEvent) {
+        if self.json_trace.is_some() {
+            self.traced_access = Some(event);
+        }
+        if let Some(log) = &mut self.memory_log {
+            log.push(event);
+        }
+    }
+
+    pub fn fence(&mut self) {
+        let pc = self.pc.current();
+        self.log_memory(MemEvent::Fence { pc });
+    }
+
+    // Warnings reported so far, oldest first.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Disables the C extension, so instructions have to be 4-byte aligned
+    // and compressed encodings are invalid, like on plain RV32I.
+    pub fn without_compressed(mut self) -> Self {
+        self.compressed = false;
+        self
+    }
+
+    // Instructions are 2-byte aligned with the C extension and 4-byte aligned without it.
+    fn inst_alignment(&self) -> u32 {
+        if self.compressed { 2 } else { 4 }
+    }
+
+    // Keeps every decoded instruction, so loops don't decode the same
+    // instructions over and over. An entry is only used while memory still
+    // holds the same encoding, so self-modifying code keeps working.
+    pub fn with_decode_cache(mut self) -> Self {
+        self.decode_cache = Some(vec![None; self.mem.len() / 2]);
+        self
+    }
+
+    // Runs on `mem` instead of the default `MEM_SIZE` bytes,
+    // e.g. one made with `Memory::from_buffer`. The stack starts at its end.
+    pub fn with_memory(mut self, mem: Memory) -> Self {
+        self.regs.write(2, mem.len() as u32);
+        self.mem = mem;
+        if self.decode_cache.is_some() {
+            self = self.with_decode_cache();
+        }
+        if self.block_cache.is_some() {
+            self = self.with_block_cache();
+        }
+        self
+    }
+
+    // Compiles straight-line runs of instructions into chains of closures
+    // over the decoded instructions the first time `run` enters them, and
+    // runs those instead of fetching and decoding each one. A block ends
+    // after a branch or jump, or before an instruction only the plain
+    // interpreter executes (syscalls, `ebreak`, custom instructions).
+    // Stores to cached code drop the blocks, direct writes to `mem` aren't
+    // seen until the next `run`. Anything observing single instructions
+    // (hooks, traces, coverage, paging, ...) falls back to the interpreter.
+    pub fn with_block_cache(mut self) -> Self {
+        self.block_cache = Some(BlockCache::new(self.mem.len()));
+        self
+    }
+
+    pub fn block_cache(&self) -> Option<&BlockCache> {
+        self.block_cache.as_ref()
+    }
+
+    fn decode_cached(&mut self, pc: u32, raw_inst: u32) -> Result<Inst, Error> {
+        let slot = (pc / 2) as usize;
+        if let Some(Some((raw, inst))) = self.decode_cache.as_ref().and_then(|c| c.get(slot))
+            && *raw == raw_inst
+        {
+            return Ok(inst.clone());
+        }
+        let inst = self.decode_raw(raw_inst)?;
+        if let Some(entry) = self.decode_cache.as_mut().and_then(|c| c.get_mut(slot)) {
+            *entry = Some((raw_inst, inst.clone()));
+        }
+        Ok(inst)
+    }
+
+    // Sets the address of the next instruction to execute.
+    pub fn set_pc(&mut self, addr: u32) -> Result<(), Error> {
+        if !addr.is_multiple_of(self.inst_alignment()) {
+            return Err(Error::InstructionAddressMisaligned(addr));
+        }
+        self.pc.set(addr);
+        Ok(())
+    }
+
+    // Validation aid: executing an instruction whose mnemonic (e.g. `addi`,
+    // never a pseudo-instruction) isn't in `mnemonics` raises `DisallowedInstruction`.
+    pub fn with_allowed_instructions<'a>(
+        mut self,
+        mnemonics: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        self.allowed_insts = Some(mnemonics.into_iter().map(String::from).collect());
+        self
+    }
+
+    // Enables the Zbb (basic bit-manipulation) extension.
+    // Without it its encodings are invalid instructions, like on plain RV32I.
+    pub fn with_zbb(mut self) -> Self {
+        self.zbb = true;
+        self
+    }
+
+    // Maps a SiFive test finisher at `addr` (usually `TEST_FINISHER_ADDR`),
+    // so programs can exit by storing to it instead of using `ecall`.
+    pub fn with_test_finisher(mut self, addr: u32) -> Self {
+        self.finisher = Some(TestFinisher::new(addr));
+        self
+    }
+
+    // Maps a `width` x `height` framebuffer at `addr` (usually `FRAMEBUFFER_ADDR`).
+    pub fn with_framebuffer(
+        mut self,
+        addr: u32,
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+    ) -> Self {
+        self.framebuffer = Some(Framebuffer::new(addr, width, height, format));
+        self
+    }
+
+    // Pixels written so far, empty if there's no framebuffer.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.framebuffer.as_ref().map_or(&[], Framebuffer::pixels)
+    }
+
+    // Debugging aid: any store overlapping `range` fails with `WriteToProtectedRegion`
+    // until the protection is lifted again, e.g. between steps.
+    pub fn memory_write_protect(&mut self, range: Range<u32>) {
+        self.write_protected.push(range);
+    }
+
+    pub fn memory_write_unprotect(&mut self, range: Range<u32>) {
+        self.write_protected.retain(|protected| *protected != range);
+    }
+
+    // Stops the run after a load and/or store touches `addr`.
+    // The access itself completes, so the loaded or stored value can be inspected.
+    pub fn add_watchpoint(&mut self, addr: u32, kind: WatchKind) {
+        self.watchpoints.insert(addr, kind);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) {
+        self.watchpoints.remove(&addr);
+    }
+
+    // Calls `hook` every time execution reaches `addr`, before the instruction
+    // there is fetched. The hook may change registers and memory or move the PC,
+    // e.g. set it to `ra` to return from an intercepted function.
+    // Replaces any hook previously registered at `addr`.
+    pub fn on_pc(&mut self, addr: u32, hook: PcHook) {
+        self.pc_hooks.insert(addr, hook);
+    }
+
+    // Calls `hook` once per cycle with the instruction about to execute and
+    // the registers changed since the last call, i.e. by the previous instruction.
+    pub fn on_cycle(&mut self, hook: CycleHook) {
+        self.cycle_hook = Some((hook, self.regs.dump_all()));
+    }
+
+    fn run_cycle_hook(&mut self, pc: u32, inst: &Inst) {
+        let Some((hook, last)) = &mut self.cycle_hook else {
+            return;
+        };
+        let regs = self.regs.dump_all();
+        let changed = (0..32)
+            .filter(|&reg| regs[reg] != last[reg])
+            .map(|reg| (reg, regs[reg]))
+            .collect();
+        *last = regs;
+        hook(&CycleSnapshot {
+            cycle: self.cycles,
+            pc,
+            inst: inst.clone(),
+            changed,
+        });
+    }
+
+    // Routes the custom opcodes to `decoder`, they are invalid without one.
+    pub fn with_custom_decoder(mut self, decoder: CustomDecoder) -> Self {
+        self.custom_decoder = Some(decoder);
+        self
+    }
+
+    pub fn execute_custom(&mut self, raw_inst: u32) -> Result<(), Error> {
+        let opcode = get_bits!(raw_inst, 0, 6);
+        // taken out for the duration of the call, like PC hooks
+        let Some(mut decoder) = self.custom_decoder.take() else {
+            return Err(Error::InvalidOpcode(opcode));
+        };
+        let result = decoder(self, raw_inst);
+        self.custom_decoder.get_or_insert(decoder);
+        result
+    }
+
+    fn run_pc_hook(&mut self) {
+        let pc = self.pc.get();
+        // taken out for the duration of the call, since it borrows the CPU
+        if let Some(mut hook) = self.pc_hooks.remove(&pc) {
+            hook(self);
+            // unless the hook registered a new one for the same address
+            self.pc_hooks.entry(pc).or_insert(hook);
+        }
+    }
+
+    fn check_watchpoints(&mut self, addr: u32, size: Size, is_write: bool) {
+        for offset in 0..size as u32 {
+            let watched = addr.wrapping_add(offset);
+            match self.watchpoints.get(&watched) {
+                Some(WatchKind::Access) => (),
+                Some(WatchKind::Write) if is_write => (),
+                Some(WatchKind::Read) if !is_write => (),
+                _ => continue,
+            }
+            self.watch_hit = Some(watched);
+            return;
+        }
+    }
+
+    // Enables Sv32 address translation when its top bit is set, see `mmu`.
+    // Flushes the TLB, as the old translations may belong to another address space.
+    pub fn set_satp(&mut self, satp: u32) {
+        self.satp = satp;
+        self.tlb.flush();
+    }
+
+    pub fn satp(&self) -> u32 {
+        self.satp
+    }
+
+    // Value of a CSR for the CSR instructions, `None` if it isn't implemented.
+    pub fn csr_read(&self, csr: u32) -> Option<u32> {
+        match csr {
+            SATP => Some(self.satp),
+            CYCLE | INSTRET => Some(self.cycles as u32),
+            CYCLEH | INSTRETH => Some((self.cycles >> 32) as u32),
+            _ => None,
+        }
+    }
+
+    // Writes a CSR that `csr_read` implements and isn't read-only.
+    pub fn csr_write(&mut self, csr: u32, val: u32) {
+        if csr == SATP {
+            self.set_satp(val);
+        }
+    }
+
+    pub fn tlb(&self) -> &Tlb {
+        &self.tlb
+    }
+
+    // Flushes the TLB entries of `vaddr` and `asid`, `None` matches any.
+    pub fn sfence_vma(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        self.tlb.flush_matching(vaddr, asid);
+    }
+
+    // Physical address of `addr`, which is the same address unless paging is enabled.
+    fn translate(&mut self, addr: u32, access: Access) -> Result<u32, Error> {
+        if !mmu::is_enabled(self.satp) {
+            return Ok(addr);
+        }
+        self.tlb.translate(&self.mem, self.satp, addr, access)
+    }
+
+    pub fn load(&mut self, addr: u32, size: Size, is_unsigned: bool) -> Result<u32, Error> {
+        let addr = self.translate(addr, Access::Load)?;
+        let val = self.mem.read(addr, size, is_unsigned)?;
+        if !self.mem.is_initialized(addr, size) {
+            return Err(Error::UninitializedRead(addr));
+        }
+        self.check_watchpoints(addr, size, false);
+        let pc = self.pc.current();
+        self.log_memory(MemEvent::Load { pc, addr, val });
+        Ok(val)
+    }
+
+    pub fn store(&mut self, addr: u32, size: Size, val: u32) -> Result<(), Error> {
+        let addr = self.translate(addr, Access::Store)?;
+        let end = addr.saturating_add(size as u32);
+        if self.write_protected.iter().any(|range| addr < range.end && end > range.start) {
+            return Err(Error::WriteToProtectedRegion(addr));
+        }
+        self.route_store(addr, size, val)?;
+        if let Some(blocks) = &mut self.block_cache {
+            blocks.invalidate(addr, size as u32);
+        }
+        let pc = self.pc.current();
+        self.log_memory(MemEvent::Store { pc, addr, val });
+        Ok(())
+    }
+
+    // Routes stores either to a memory-mapped device or to memory.
+    fn route_store(&mut self, addr: u32, size: Size, val: u32) -> Result<(), Error> {
+        if let Some(finisher) = &self.finisher
+            && finisher.addr == addr
+        {
+            self.exit_request = finisher.exit_code(val);
+            return Ok(());
+        }
+        if let Some(framebuffer) = &mut self.framebuffer
+            && framebuffer.contains(addr, size as usize)
+        {
+            if !framebuffer.write(addr, size as usize, val) {
+                return Err(Error::StoreAccessFault(addr, self.mem.len()));
+            }
+            return Ok(());
+        }
+        self.mem.write(addr, size, val)?;
+        self.check_watchpoints(addr, size, true);
+        Ok(())
+    }
+
+    // Lets another thread (e.g. a Ctrl-C handler) interrupt a run by setting `signal`.
+    // It's checked before every instruction and cleared once observed,
+    // so the run can be resumed afterwards.
+    pub fn with_stop_signal(mut self, signal: Arc<AtomicBool>) -> Self {
+        self.stop_signal = Some(signal);
+        self
+    }
+
+    // Enables counting of modeled pipeline stalls, see `Pipeline`.
+    pub fn with_pipeline_model(mut self) -> Self {
+        self.pipeline = Some(Pipeline::default());
+        self
+    }
+
+    pub fn pipeline_stats(&self) -> Option<&PipelineStats> {
+        self.pipeline.as_ref().map(Pipeline::stats)
+    }
+
+    // Counts executed instructions by mnemonic, see `histogram`.
+    pub fn with_histogram(mut self) -> Self {
+        self.histogram = Some(HashMap::new());
+        self
+    }
+
+    // Executed instructions per mnemonic, `nop`s are counted separately from
+    // `addi` to tell padding from arithmetic.
+    pub fn histogram(&self) -> Option<&HashMap<String, u64>> {
+        self.histogram.as_ref()
+    }
+
+    // Symbols used to annotate addresses in dumps.
+    pub fn with_symbols(mut self, symbols: SymbolMap) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Formats `addr` as `0x1014 <main+0x4>`, or just the address without a symbol.
+    pub fn symbolize(&self, addr: u32) -> String {
+        match self.symbols.lookup(addr) {
+            Some(symbol) => format!("{addr:#x} <{symbol}>"),
+            None => format!("{addr:#x}"),
         }
     }
 
+    // Limits what the program is allowed to do,
+    // see `SandboxPolicy` for details.
+    pub fn with_sandbox(mut self, policy: SandboxPolicy) -> Self {
+        self.sandbox = policy;
+        self
+    }
+
+    // Prefer these over reaching into `mem` directly,
+    // the concrete memory type may change in future.
+    pub fn memory(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+
+    // Register access for host tools (debugger stub, REPL, tests).
+    // Writes to `x0` are ignored, indices past `x31` are an error.
+    pub fn get_register(&self, reg: usize) -> Result<u32, Error> {
+        self.regs.try_read(reg)
+    }
+
+    pub fn set_register(&mut self, reg: usize, val: u32) -> Result<(), Error> {
+        self.regs.try_write(reg, val)
+    }
+
+    // Keeps the last `size` executed instructions for post-mortem analysis.
+    pub fn with_history(mut self, size: usize) -> Self {
+        self.history = Some(VecDeque::with_capacity(size));
+        self.history_size = size;
+        self
+    }
+
+    // Returns the retained `(pc, raw_inst)` pairs, oldest first.
+    // Empty if history isn't enabled.
+    pub fn recent_instructions(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.history.iter().flatten().copied()
+    }
+
+    // Seeds the generator behind the `getrandom` syscall.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = XorShift::new(seed);
+        self
+    }
+
+    // Sets the clock rate behind `clock_gettime`. Time is derived from
+    // the number of executed instructions, so it's the same on every run.
+    pub fn with_frequency(mut self, hz: u64) -> Self {
+        assert!(hz > 0, "frequency must be positive");
+        self.frequency = hz;
+        self
+    }
+
+    // Sets how misaligned loads/stores are handled.
+    // They are emulated transparently by default.
+    pub fn with_misaligned_access(mut self, policy: MisalignedAccess) -> Self {
+        self.misaligned_access = policy;
+        self
+    }
+
+    pub fn misaligned_access(&self) -> MisalignedAccess {
+        self.misaligned_access
+    }
+
+    // Debugging aid: loads of memory that was never stored to
+    // (or loaded as part of the program) raise an error.
+    pub fn with_uninitialized_read_trap(mut self) -> Self {
+        self.mem.track_initialized();
+        self
+    }
+
+    // Debugging aid: moving the stack pointer below `limit` raises an error,
+    // catching runaway recursion before the stack grows into the data beneath it.
+    pub fn with_stack_guard(mut self, limit: u32) -> Self {
+        self.stack_guard = Some(limit);
+        self
+    }
+
+    // Hash of the registers, PC and memory. Two runs that end with
+    // the same hash ended in the same machine state.
+    pub fn state_hash(&self) -> u64 {
+        let hash = fnv1a(self.mem.checksum(), &self.pc.get().to_le_bytes());
+        (0..32).fold(hash, |hash, reg| fnv1a(hash, &self.regs.read(reg).to_le_bytes()))
+    }
+
+    // Fills registers with a poison pattern instead of zeros
+    // to make reads of uninitialized registers stand out.
+    pub fn with_poisoned_registers(mut self) -> Self {
+        self.regs = Registers::poisoned(self.mem.len() as u32);
+        self
+    }
+
+    // Enables recording of every executed instruction address.
+    pub fn with_coverage(mut self) -> Self {
+        self.coverage = Some(HashSet::new());
+        self
+    }
+
+    // Returns addresses of instructions executed at least once,
+    // if coverage is enabled.
+    pub fn coverage(&self) -> Option<&HashSet<u32>> {
+        self.coverage.as_ref()
+    }
+
+    // Fails without running anything if the program is empty or its size isn't a
+    // multiple of 2 bytes (4 without the C extension), as it can't hold whole instructions.
     pub fn run(&mut self, program: Vec<u8>) -> Result<u8, Error> {
+        if program.is_empty() {
+            return Err(Error::EmptyProgram);
+        }
+        if !program.len().is_multiple_of(self.inst_alignment() as usize) {
+            return Err(Error::MisalignedProgram(program.len()));
+        }
         self.mem.load_program(program);
+        self.emulate()
+    }
+
+    // Same as `run`, but also returns the state the program left behind.
+    pub fn run_to_completion(&mut self, program: Vec<u8>) -> Result<RunResult<'_>, Error> {
+        let exit_code = self.run(program)?;
+        Ok(RunResult {
+            exit_code,
+            regs: self.regs.dump_all(),
+            pc: self.pc.get(),
+            cycles: self.cycles,
+            mem: &self.mem,
+        })
+    }
+
+    // Loads a program from a streaming source, e.g. stdin, and runs it.
+    // Fails without running anything if the program doesn't fit in memory.
+    pub fn run_reader(&mut self, reader: impl Read) -> Result<u8, Error> {
+        let mut program = Vec::new();
+        // one byte over the limit is enough to tell the program is too large
+        reader
+            .take(self.mem.len() as u64 + 1)
+            .read_to_end(&mut program)
+            .map_err(Error::Io)?;
+        if program.len() > self.mem.len() {
+            return Err(Error::ProgramTooLarge(self.mem.len()));
+        }
+        self.run(program)
+    }
+
+    // Runs program from a file, which is either an ELF executable
+    // or a flat binary loaded to the start of the memory.
+    pub fn load_and_run(&mut self, path: impl AsRef<Path>) -> Result<u8, Error> {
+        let program = fs::read(path).map_err(Error::Io)?;
+        if !elf::is_elf(&program) {
+            return self.run(program);
+        }
+        let elf = Elf::parse(&program, self.mem.len())?;
+        self.mem.load_program(elf.image);
+        self.set_pc(elf.entry)?;
+        // what crt0 would do, so that gp-relative accesses work
+        if let Some(gp) = elf.global_pointer {
+            self.regs.write(3, gp);
+        }
+        self.emulate()
+    }
+
+    // runs loaded program until it exits or fails
+    fn emulate(&mut self) -> Result<u8, Error> {
+        // the program was loaded straight into memory
+        if let Some(blocks) = &mut self.block_cache {
+            blocks.flush();
+        }
         for cycle in 0.. {
-            match self.emulate_cycle() {
-                Ok(ProgState::Exit(code)) => {
-                    self.dump_state(cycle);
+            let outcome = match self.blocks_usable() {
+                true => self.run_block(),
+                false => self.emulate_cycle(),
+            };
+            match outcome {
+                Ok(StepOutcome::Exit(code)) => {
+                    self.log_state(cycle);
                     return Ok(code);
                 }
+                Ok(StepOutcome::Breakpoint(pc)) => {
+                    self.log_state(cycle);
+                    return Err(Error::Breakpoint(pc));
+                }
+                Ok(StepOutcome::Watchpoint(addr)) => {
+                    self.log_state(cycle);
+                    return Err(Error::Watchpoint(addr));
+                }
+                Ok(StepOutcome::Interrupted) => {
+                    self.log_state(cycle);
+                    return Err(Error::Interrupted(self.pc.get()));
+                }
                 Err(e) => {
-                    self.dump_state(cycle);
+                    self.log_state(cycle);
                     return Err(e);
                 }
                 // TODO: why is it returning unit type?
                 _ => (),
             }
             if self.print_debug {
-                self.dump_state(cycle);
+                self.log_state(cycle);
             }
         }
         unreachable!("emulator should either run out of instructions or exit using syscall")
     }
 
-    fn dump_state(&self, cycle: usize) {
-        eprintln!("CPU dump at cycle {cycle}");
-        eprintln!("PC: {}", self.pc.get());
+    // Writes the PC and every register (as signed values), one per line.
+    pub fn dump_state(&self, cycle: usize, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "CPU dump at cycle {cycle}")?;
+        writeln!(writer, "PC: {}", self.symbolize(self.pc.get()))?;
         for i in 0..32 {
-            eprintln!("R{i}: {}", self.regs.read(i) as i32)
+            writeln!(writer, "R{i}: {}", self.regs.read(i) as i32)?;
+        }
+        Ok(())
+    }
+
+    // `dump_state` for the run loop, the dump is best effort
+    // so a closed stderr doesn't fail the run.
+    fn log_state(&self, cycle: usize) {
+        let _ = self.dump_state(cycle, &mut io::stderr());
+    }
+
+    // Writes a canonical hexdump of `len` bytes starting at `addr`, 16 bytes per line:
+    // `00000100: 68 65 6c 6c 6f 00 00 00 00 00 00 00 00 00 00 00 | hello...........`
+    // The region is clamped to memory bounds.
+    pub fn dump_memory(&self, addr: u32, len: usize, writer: &mut impl Write) -> io::Result<()> {
+        let from = (addr as usize).min(self.mem.len());
+        let to = from.saturating_add(len).min(self.mem.len());
+        for line in (from..to).step_by(16) {
+            let bytes: Vec<u8> = (line..to.min(line + 16))
+                .map(|a| self.mem.read(a as u32, Size::Byte, true).unwrap() as u8)
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            // pads a short last line so the ascii column stays aligned
+            writeln!(writer, "{line:08x}: {:<47} | {ascii}", hex.join(" "))?;
         }
+        Ok(())
     }
 
     // fetches next instruction from memory
     fn fetch(&mut self) -> Result<u32, Error> {
-        let pc = self.pc.inc()?;
-        Ok(self.mem.read(pc, Size::Word, true))
+        let pc = self.pc.get();
+        // e.g. a jump to a misaligned target
+        if !pc.is_multiple_of(self.inst_alignment()) {
+            return Err(Error::InstructionAddressMisaligned(pc));
+        }
+        if mmu::is_enabled(self.satp) {
+            let (mem, tlb, satp) = (&self.mem, &mut self.tlb, self.satp);
+            let raw_inst =
+                read_translated(mem, pc, |addr| tlb.translate(mem, satp, addr, Access::Fetch))?;
+            // the virtual PC isn't bounded by the memory size, only its translation
+            self.pc.advance(if raw_inst & 0b11 == 0b11 { 4 } else { 2 });
+            return Ok(raw_inst);
+        }
+        // The two lowest bits of every 32-bit instruction are `11`,
+        // anything else marks a 16-bit compressed (RVC) instruction.
+        let half = self.mem.read(pc, Size::HalfWord, true);
+        let is_compressed = matches!(half, Ok(half) if half & 0b11 != 0b11);
+        let (len, size) = if is_compressed {
+            (2, Size::HalfWord)
+        } else {
+            (4, Size::Word)
+        };
+        // first half of a 32-bit instruction is the last halfword in memory
+        if half.is_ok() && !is_compressed && pc as usize + 4 > self.mem.len() {
+            return Err(Error::TruncatedInstruction(pc));
+        }
+        let pc = self.pc.inc(len, self.mem.len())?;
+        self.mem.read(pc, size, true)
+    }
+
+    // Decodes and returns the instruction at the PC without executing it.
+    // Nothing changes, not even on a fetch or decode error.
+    pub fn peek(&self) -> Result<Inst, Error> {
+        self.decode_raw(self.read_inst(self.pc.get())?)
+    }
+
+    // Reads the instruction at `pc` like `fetch`, but without side effects
+    // (compressed ones zero-extended).
+    fn read_inst(&self, pc: u32) -> Result<u32, Error> {
+        if !pc.is_multiple_of(self.inst_alignment()) {
+            return Err(Error::InstructionAddressMisaligned(pc));
+        }
+        if mmu::is_enabled(self.satp) {
+            // bypasses the TLB, which would change on a miss
+            let translate = |addr| mmu::translate(&self.mem, self.satp, addr, Access::Fetch);
+            return read_translated(&self.mem, pc, translate);
+        }
+        let half = self.mem.read(pc, Size::HalfWord, true)?;
+        if half & 0b11 != 0b11 {
+            return Ok(half);
+        }
+        if pc as usize + 4 > self.mem.len() {
+            return Err(Error::TruncatedInstruction(pc));
+        }
+        self.mem.read(pc, Size::Word, true)
+    }
+
+    // Decodes either a 32-bit or a (zero-extended) compressed instruction.
+    fn decode_raw(&self, raw_inst: u32) -> Result<Inst, Error> {
+        if raw_inst & 0b11 == 0b11 {
+            return self.decode(raw_inst);
+        }
+        if !self.compressed {
+            return Err(Error::InvalidCompressedInst(raw_inst as u16));
+        }
+        self.decode_compressed(raw_inst as u16)
+    }
+
+    // Expands 16-bit compressed (RVC) instruction into
+    // the 32-bit instruction it stands for.
+    // Only a handful of encodings are supported for now.
+    fn decode_compressed(&self, raw_inst: u16) -> Result<Inst, Error> {
+        // the lowest 2 bits select the quadrant, the highest 3 bits are funct3
+        let quadrant = get_bits!(raw_inst, 0, 1);
+        let funct3 = get_bits!(raw_inst, 13, 15);
+        let inst = match (quadrant, funct3) {
+            // c.nop => addi x0, x0, 0
+            (0b01, 0b000) if raw_inst == 0x0001 => Inst::I(
+                IInst::Arith(ArithIInst::ADDI),
+                IFormat {
+                    rd: 0,
+                    funct3: 0x0,
+                    rs1: 0,
+                    imm: 0,
+                },
+            ),
+            // c.lwsp => lw rd, offset(x2)
+            // 15    13  12       11   7 6          2 1  0
+            // +--------+--------+------+------------+----+
+            // | funct3 | off[5] |  rd  |off[4:2|7:6]| 10 |
+            // +--------+--------+------+------------+----+
+            (0b10, 0b010) if get_bits!(raw_inst, 7, 11) != 0 => {
+                // offset is scaled by 4, so its lowest 2 bits are always 0
+                let imm = (get_bits!(raw_inst, 12, 12) << 5)
+                    | (get_bits!(raw_inst, 4, 6) << 2)
+                    | (get_bits!(raw_inst, 2, 3) << 6);
+                Inst::I(
+                    IInst::Mem(LoadIInst::LW),
+                    IFormat {
+                        rd: get_bits!(raw_inst, 7, 11),
+                        funct3: 0x2,
+                        rs1: 2,
+                        imm: imm as u32,
+                    },
+                )
+            }
+            (0b00, 0b000) if raw_inst == 0x0000 => return Err(Error::IllegalInstruction(0)),
+            // c.ebreak => ebreak
+            (0b10, 0b100) if raw_inst == 0x9002 => Inst::SysCall(SysCall::Break),
+            // c.swsp => sw rs2, offset(x2)
+            // 15    13 12          7 6     2 1  0
+            // +--------+------------+-------+----+
+            // | funct3 |off[5:2|7:6]|  rs2  | 10 |
+            // +--------+------------+-------+----+
+            (0b10, 0b110) => {
+                let imm = (get_bits!(raw_inst, 9, 12) << 2) | (get_bits!(raw_inst, 7, 8) << 6);
+                Inst::S(
+                    SInst::SW,
+                    SFormat {
+                        funct3: 0x2,
+                        rs1: 2,
+                        rs2: get_bits!(raw_inst, 2, 6),
+                        imm: imm as u32,
+                    },
+                )
+            }
+            _ => return Err(Error::InvalidCompressedInst(raw_inst)),
+        };
+        Ok(inst)
     }
 
     // Parses raw byte instruction into correct format.
     // For decode information see docs folder.
-    fn decode(&self, raw_inst: u32) -> Result<Inst, Error> {
+    pub fn decode(&self, raw_inst: u32) -> Result<Inst, Error> {
         // get the lowest 7 bit for the opcode
         let opcode = get_bits!(raw_inst, 0, 6);
         let inst = match opcode {
@@ -83,6 +1053,23 @@ impl Cpu {
                     (0x5, 0x20) => RInst::SRA,
                     (0x2, 0x00) => RInst::SLT,
                     (0x3, 0x00) => RInst::SLTU,
+                    (0x0, 0x01) => RInst::MUL,
+                    (0x1, 0x01) => RInst::MULH,
+                    (0x2, 0x01) => RInst::MULHSU,
+                    (0x3, 0x01) => RInst::MULHU,
+                    (0x4, 0x01) => RInst::DIV,
+                    (0x5, 0x01) => RInst::DIVU,
+                    (0x6, 0x01) => RInst::REM,
+                    (0x7, 0x01) => RInst::REMU,
+                    (0x7, 0x20) if self.zbb => RInst::ANDN,
+                    (0x6, 0x20) if self.zbb => RInst::ORN,
+                    (0x4, 0x20) if self.zbb => RInst::XNOR,
+                    (0x4, 0x05) if self.zbb => RInst::MIN,
+                    (0x5, 0x05) if self.zbb => RInst::MINU,
+                    (0x6, 0x05) if self.zbb => RInst::MAX,
+                    (0x7, 0x05) if self.zbb => RInst::MAXU,
+                    (0x1, 0x30) if self.zbb => RInst::ROL,
+                    (0x5, 0x30) if self.zbb => RInst::ROR,
                     _ => return Err(Error::InvalidInstFormat(FormatError::R(r_format))),
                 };
                 Inst::R(inst, r_format)
@@ -98,6 +1085,21 @@ impl Cpu {
                     (0x1, 0x00) => ArithIInst::SLLI,
                     (0x5, 0x00) => ArithIInst::SRLI,
                     (0x5, 0x20) => ArithIInst::SRAI,
+                    (0x5, 0x30) if self.zbb => ArithIInst::RORI,
+                    // rs2 field selects the operation
+                    (0x1, 0x30) if self.zbb => match get_bits!(i_format.imm, 0, 4) {
+                        0x0 => ArithIInst::CLZ,
+                        0x1 => ArithIInst::CTZ,
+                        0x2 => ArithIInst::CPOP,
+                        0x4 => ArithIInst::SEXTB,
+                        0x5 => ArithIInst::SEXTH,
+                        _ => return Err(Error::InvalidInstFormat(FormatError::I(i_format))),
+                    },
+                    // Any other upper bits of a shift immediate are reserved.
+                    // This includes shamt[5], a shift by 32 or more is only valid on RV64.
+                    (0x1 | 0x5, _) => {
+                        return Err(Error::InvalidInstFormat(FormatError::Shift(i_format)));
+                    }
                     (0x2, _) => ArithIInst::SLTI,
                     (0x3, _) => ArithIInst::SLTIU,
                     _ => return Err(Error::InvalidInstFormat(FormatError::I(i_format))),
@@ -126,13 +1128,10 @@ impl Cpu {
             }
             0b0100011 => {
                 let s_format = SFormat::new(raw_inst);
-                let inst = match s_format.funct3 {
-                    0x0 => SInst::SB,
-                    0x1 => SInst::SH,
-                    0x2 => SInst::SW,
-                    _ => return Err(Error::InvalidInstFormat(FormatError::S(s_format))),
+                let Some(size) = Size::from_funct3(s_format.funct3) else {
+                    return Err(Error::InvalidInstFormat(FormatError::S(s_format)));
                 };
-                Inst::S(inst, s_format)
+                Inst::S(SInst::from(size), s_format)
             }
             0b1100011 => {
                 let b_format = BFormat::new(raw_inst);
@@ -147,6 +1146,24 @@ impl Cpu {
                 };
                 Inst::B(inst, b_format)
             }
+            0b0101111 => {
+                let r_format = RFormat::new(raw_inst);
+                // funct7 holds funct5 followed by the aq/rl ordering bits
+                let funct5 = get_bits!(r_format.funct7, 2, 6);
+                let inst = match (r_format.funct3, funct5) {
+                    (0x2, 0x00) => AmoInst::AMOADD,
+                    (0x2, 0x01) => AmoInst::AMOSWAP,
+                    (0x2, 0x04) => AmoInst::AMOXOR,
+                    (0x2, 0x08) => AmoInst::AMOOR,
+                    (0x2, 0x0c) => AmoInst::AMOAND,
+                    (0x2, 0x10) => AmoInst::AMOMIN,
+                    (0x2, 0x14) => AmoInst::AMOMAX,
+                    (0x2, 0x18) => AmoInst::AMOMINU,
+                    (0x2, 0x1c) => AmoInst::AMOMAXU,
+                    _ => return Err(Error::InvalidInstFormat(FormatError::R(r_format))),
+                };
+                Inst::Amo(inst, r_format)
+            }
             0b1101111 => {
                 // JAL instruction is the only J-Format instruction
                 Inst::J(JFormat::new(raw_inst))
@@ -154,37 +1171,2255 @@ impl Cpu {
             0b0110111 => Inst::U(UInst::LUI, UFormat::new(raw_inst)),
             0b0010111 => Inst::U(UInst::AUIPC, UFormat::new(raw_inst)),
             0b1110011 => {
-                // ecall
-                let call = if self.regs.read(17) == 93 {
-                    // intercept exit syscall (a7 == 93) to check official risc-v test suite
-                    SysCall::Exit(self.regs.read(10) as u8)
-                } else {
-                    SysCall::Nop
-                };
-                Inst::SysCall(call)
+                let i_format = IFormat::new(raw_inst);
+                match i_format.funct3 {
+                    0x0 if i_format.rd == 0 && get_bits!(raw_inst, 25, 31) == 0x09 => {
+                        Inst::SfenceVma(RFormat::new(raw_inst))
+                    }
+                    // rd and rs1 have to be zero, imm[11:0] (funct12) selects the instruction
+                    0x0 if i_format.rd == 0 && i_format.rs1 == 0 => {
+                        let call = match get_bits!(raw_inst, 20, 31) {
+                            0x000 => SysCall::Ecall,
+                            0x001 => SysCall::Break,
+                            0x102 => SysCall::Sret,
+                            0x302 => SysCall::Mret,
+                            0x105 => SysCall::Wfi,
+                            _ => return Err(Error::InvalidInstFormat(FormatError::I(i_format))),
+                        };
+                        Inst::SysCall(call)
+                    }
+                    0x1 if raw_inst == UNIMP => return Err(Error::IllegalInstruction(raw_inst)),
+                    0x1 => Inst::Csr(CsrInst::CSRRW, i_format),
+                    0x2 => Inst::Csr(CsrInst::CSRRS, i_format),
+                    0x3 => Inst::Csr(CsrInst::CSRRC, i_format),
+                    0x5 => Inst::Csr(CsrInst::CSRRWI, i_format),
+                    0x6 => Inst::Csr(CsrInst::CSRRSI, i_format),
+                    0x7 => Inst::Csr(CsrInst::CSRRCI, i_format),
+                    _ => return Err(Error::InvalidInstFormat(FormatError::I(i_format))),
+                }
             }
             0b0001111 => {
                 // fence (also necessary for RISC-V tests)
-                Inst::SysCall(SysCall::Nop)
+                // imm[11:0] = fm[3:0] | pred[3:0] | succ[3:0]
+                // Unknown combinations are hints and decode as a plain fence.
+                let inst = if raw_inst == 0x0100000f {
+                    FenceInst::PAUSE
+                } else if get_bits!(raw_inst, 20, 31) == 0x833 {
+                    FenceInst::TSO
+                } else {
+                    FenceInst::FENCE
+                };
+                Inst::Fence(inst)
+            }
+            // custom-0, custom-1, custom-2 and custom-3
+            0b0001011 | 0b0101011 | 0b1011011 | 0b1111011 if self.custom_decoder.is_some() => {
+                Inst::Custom(raw_inst)
             }
             _ => return Err(Error::InvalidOpcode(opcode)),
         };
         Ok(inst)
     }
 
-    fn emulate_cycle(&mut self) -> Result<ProgState, Error> {
-        let raw_inst = self.fetch()?;
-        if raw_inst == 0 {
-            return Err(Error::EndOfInstructions);
-        }
-        if self.print_debug {
-            eprintln!("Instruction: {:032b}", raw_inst);
-        }
+    // Same as `decode`, but also returns the encoding fields.
+    pub fn decode_info(&self, raw_inst: u32) -> Result<DecodedInst, Error> {
         let inst = self.decode(raw_inst)?;
-        if let Inst::SysCall(SysCall::Exit(code)) = inst {
-            return Ok(ProgState::Exit(code))
+        Ok(DecodedInst::new(inst, get_bits!(raw_inst, 0, 6)))
+    }
+
+    // Decodes a whole program without executing it (linear sweep),
+    // returning every instruction together with its address.
+    pub fn decode_all(&self, program: &[u8]) -> Vec<(u32, Result<Inst, Error>)> {
+        let mut insts = Vec::new();
+        let mut addr = 0;
+        while addr + 2 <= program.len() {
+            let half = u16::from_le_bytes([program[addr], program[addr + 1]]);
+            if half & 0b11 != 0b11 {
+                insts.push((addr as u32, self.decode_compressed(half)));
+                addr += 2;
+                continue;
+            }
+            let Some(bytes) = program.get(addr..addr + 4) else {
+                insts.push((addr as u32, Err(Error::TruncatedInstruction(addr as u32))));
+                break;
+            };
+            let raw_inst = u32::from_le_bytes(bytes.try_into().unwrap());
+            insts.push((addr as u32, self.decode(raw_inst)));
+            addr += 4;
         }
-        inst.execute(self);
-        Ok(ProgState::Continue)
+        insts
+    }
+
+    // Decodes and executes a single instruction as if it was fetched
+    // from the current PC, without reading it from memory.
+    pub fn execute_raw(&mut self, raw_inst: u32) -> Result<StepOutcome, Error> {
+        let pc = self.pc.get();
+        let len = if raw_inst & 0b11 != 0b11 { 2 } else { 4 };
+        // Executed instructions expect the PC to already point past
+        // them (see `fetch`), so PC-relative ones use the current PC.
+        self.pc.advance(len);
+        self.execute(pc, raw_inst)
+    }
+
+    // Logs every executed instruction word to `writer` (4 bytes, little-endian,
+    // compressed ones zero-extended), so the run can be reproduced with `replay`.
+    pub fn record(&mut self, writer: impl Write + 'static) {
+        self.recorder = Some(Box::new(writer));
+    }
+
+    // Writes a JSON line per executed instruction to `writer`, see `TraceStep`.
+    #[cfg(feature = "serde")]
+    pub fn trace_json(&mut self, writer: impl Write + 'static) {
+        self.json_trace = Some(Box::new(writer));
+    }
+
+    // Re-executes an instruction log written by `record` through `execute_raw`
+    // until it ends or an instruction does something other than continue.
+    // Only the instruction stream is replayed, memory isn't fetched from,
+    // so self-modifying code won't replay faithfully.
+    pub fn replay(&mut self, mut reader: impl Read) -> Result<StepOutcome, Error> {
+        let mut raw_inst = [0; 4];
+        loop {
+            match reader.read_exact(&mut raw_inst) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(StepOutcome::Continue);
+                }
+                Err(e) => return Err(Error::Io(e)),
+            }
+            match self.execute_raw(u32::from_le_bytes(raw_inst))? {
+                StepOutcome::Continue => (),
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    // Runs until the next `ecall` and stops right before it, so the host can
+    // inspect the syscall or change its arguments (strace-like tracing).
+    // The following call executes that `ecall` and runs to the next one.
+    pub fn continue_until_syscall(&mut self) -> Result<SyscallStop, Error> {
+        let mut resuming = self.syscall_entry.take() == Some(self.pc.get());
+        loop {
+            let pc = self.pc.get();
+            let is_ecall = matches!(self.peek(), Ok(Inst::SysCall(SysCall::Ecall)));
+            if is_ecall && !resuming {
+                self.syscall_entry = Some(pc);
+                let mut args = [0; 6];
+                for (i, arg) in args.iter_mut().enumerate() {
+                    *arg = self.regs.read(10 + i);
+                }
+                let number = self.regs.read(17);
+                return Ok(SyscallStop::Entry(SyscallEntry { pc, number, args }));
+            }
+            resuming = false;
+            match self.step()? {
+                StepOutcome::Continue => (),
+                outcome => return Ok(SyscallStop::Done(outcome)),
+            }
+        }
+    }
+
+    // Fetches and executes a single instruction.
+    pub fn step(&mut self) -> Result<StepOutcome, Error> {
+        self.emulate_cycle()
+    }
+
+    // Runs `program` in lockstep with a reference trace, one step per record,
+    // and returns the first step that doesn't match it.
+    // Stops without a divergence when the trace ends or the program exits.
+    pub fn run_checked(
+        &mut self,
+        program: Vec<u8>,
+        reference: impl IntoIterator<Item = CommitRecord>,
+    ) -> Result<Option<Divergence>, Error> {
+        self.mem.load_program(program);
+        for expected in reference {
+            let pc = self.pc.get();
+            let outcome = self.step()?;
+            let write = match expected.write {
+                Some((reg, _)) => Some((reg, self.regs.try_read(reg)?)),
+                None => None,
+            };
+            let actual = CommitRecord { pc, write };
+            if actual != expected {
+                return Ok(Some(Divergence { expected, actual }));
+            }
+            if outcome != StepOutcome::Continue {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // Executes up to `count` instructions, stopping early on anything
+    // other than `StepOutcome::Continue`. Returns how many instructions
+    // were executed (a failed one isn't counted) and the last outcome.
+    pub fn step_n(&mut self, count: usize) -> (usize, Result<StepOutcome, Error>) {
+        let mut outcome = Ok(StepOutcome::Continue);
+        for ran in 0..count {
+            outcome = self.emulate_cycle();
+            match outcome {
+                Ok(StepOutcome::Continue) => (),
+                Ok(StepOutcome::Interrupted) | Err(_) => return (ran, outcome),
+                Ok(_) => return (ran + 1, outcome),
+            }
+        }
+        (count, outcome)
+    }
+
+    // Whether `run` may execute blocks, which skips everything
+    // that happens around single instructions in `emulate_cycle`.
+    fn blocks_usable(&self) -> bool {
+        self.block_cache.is_some()
+            && !self.print_debug
+            && !mmu::is_enabled(self.satp)
+            && self.coverage.is_none()
+            && self.history.is_none()
+            && self.recorder.is_none()
+            && self.json_trace.is_none()
+            && self.pipeline.is_none()
+            && self.histogram.is_none()
+            && self.cycle_hook.is_none()
+            && self.pc_hooks.is_empty()
+    }
+
+    // Runs the block at the PC, compiling it on the first entry. Where no
+    // block starts, e.g. at a syscall, a single instruction is interpreted.
+    fn run_block(&mut self) -> Result<StepOutcome, Error> {
+        if let Some(signal) = &self.stop_signal
+            && signal.swap(false, Ordering::Relaxed)
+        {
+            return Ok(StepOutcome::Interrupted);
+        }
+        let Some(blocks) = &self.block_cache else {
+            return self.emulate_cycle();
+        };
+        let entry = self.pc.get();
+        let generation = blocks.generation();
+        let block = match blocks.get(entry) {
+            Some(block) => block,
+            None => {
+                let block = self.compile_block(entry);
+                self.block_cache.as_mut().unwrap().insert(entry, block)
+            }
+        };
+        if block.ops.is_empty() {
+            return self.emulate_cycle();
+        }
+        // what `emulate_cycle` and `execute_decoded` do around each instruction
+        for (len, op) in &block.ops {
+            if !sandbox::within(self.sandbox.max_cycles, self.cycles + 1) {
+                return Err(Error::PolicyViolation(PolicyViolation::Cycles));
+            }
+            self.cycles += 1;
+            self.pc.advance(*len);
+            op(self)?;
+            if let Some(limit) = self.stack_guard
+                && self.regs.read(2) < limit
+            {
+                return Err(Error::StackOverflow(self.regs.read(2)));
+            }
+            if let Some(code) = self.exit_request.take() {
+                return Ok(StepOutcome::Exit(code));
+            }
+            if let Some(addr) = self.watch_hit.take() {
+                return Ok(StepOutcome::Watchpoint(addr));
+            }
+            // the block overwrote code, maybe its own
+            if self.block_cache.as_ref().map(BlockCache::generation) != Some(generation) {
+                break;
+            }
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    // Decodes the instructions from `entry` on into a block, up to and
+    // including the first branch, jump or CSR access. Whatever can't be compiled ends
+    // the block before it and is left to `emulate_cycle`, errors included.
+    fn compile_block(&self, entry: u32) -> Block {
+        let mut block = Block::default();
+        let mut pc = entry;
+        while block.ops.len() < MAX_BLOCK_LEN {
+            let Ok(raw_inst) = self.read_inst(pc) else {
+                break;
+            };
+            let Ok(inst) = self.decode_raw(raw_inst) else {
+                break;
+            };
+            let allowed = match &self.allowed_insts {
+                Some(allowed) => allowed.contains(&inst.mnemonic()),
+                None => true,
+            };
+            if raw_inst == 0 || !allowed || matches!(inst, Inst::SysCall(_) | Inst::Custom(_)) {
+                break;
+            }
+            let len = if raw_inst & 0b11 == 0b11 { 4 } else { 2 };
+            // a CSR write may turn on paging, which blocks don't support
+            let ends_block =
+                inst.is_control_flow() || matches!(inst, Inst::Csr(..) | Inst::SfenceVma(_));
+            let op: BlockOp = Box::new(move |cpu| inst.clone().execute(cpu));
+            block.ops.push((len, op));
+            if ends_block {
+                break;
+            }
+            pc = pc.wrapping_add(len);
+        }
+        block
+    }
+
+    fn emulate_cycle(&mut self) -> Result<StepOutcome, Error> {
+        if let Some(signal) = &self.stop_signal
+            && signal.swap(false, Ordering::Relaxed)
+        {
+            return Ok(StepOutcome::Interrupted);
+        }
+        if !sandbox::within(self.sandbox.max_cycles, self.cycles + 1) {
+            return Err(Error::PolicyViolation(PolicyViolation::Cycles));
+        }
+        self.cycles += 1;
+        self.run_pc_hook();
+        let pc = self.pc.get();
+        let raw_inst = self.fetch()?;
+        if raw_inst == 0 {
+            return Err(Error::EndOfInstructions);
+        }
+        if self.print_debug {
+            eprintln!("Instruction: {:032b}", raw_inst);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.insert(pc);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.write_all(&raw_inst.to_le_bytes()).map_err(Error::Io)?;
+        }
+        if let Some(history) = &mut self.history {
+            if history.len() == self.history_size {
+                history.pop_front();
+            }
+            if self.history_size > 0 {
+                history.push_back((pc, raw_inst));
+            }
+        }
+        self.execute(pc, raw_inst)
+    }
+
+    fn execute(&mut self, pc: u32, raw_inst: u32) -> Result<StepOutcome, Error> {
+        let inst = self.decode_cached(pc, raw_inst)?;
+        self.run_cycle_hook(pc, &inst);
+        #[cfg(feature = "serde")]
+        if self.json_trace.is_some() {
+            return self.execute_traced(pc, raw_inst, inst);
+        }
+        self.execute_decoded(pc, inst)
+    }
+
+    #[cfg(feature = "serde")]
+    fn execute_traced(&mut self, pc: u32, raw_inst: u32, inst: Inst) -> Result<StepOutcome, Error> {
+        let mnemonic = inst.mnemonic();
+        let rd = inst.writes();
+        self.traced_access = None;
+        let outcome = self.execute_decoded(pc, inst)?;
+        let step = TraceStep {
+            pc,
+            raw_inst,
+            mnemonic,
+            rd,
+            value: rd.map(|rd| self.regs.read(rd)),
+            mem: self.traced_access.take().and_then(TraceAccess::new),
+        };
+        if let Some(writer) = &mut self.json_trace {
+            writeln!(writer, "{}", step.to_json()).map_err(Error::Io)?;
+        }
+        Ok(outcome)
+    }
+
+    fn execute_decoded(&mut self, pc: u32, inst: Inst) -> Result<StepOutcome, Error> {
+        if let Some(allowed) = &self.allowed_insts
+            && !allowed.contains(&inst.mnemonic())
+        {
+            return Err(Error::DisallowedInstruction(inst.mnemonic(), pc));
+        }
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.issue(&inst);
+        }
+        if let Some(histogram) = &mut self.histogram {
+            let mnemonic = if inst.is_nop() { "nop".to_string() } else { inst.mnemonic() };
+            *histogram.entry(mnemonic).or_default() += 1;
+        }
+        match inst {
+            Inst::SysCall(SysCall::Ecall) => return self.syscall(),
+            Inst::SysCall(SysCall::Break) => return Ok(StepOutcome::Breakpoint(pc)),
+            _ => (),
+        }
+        let is_control_flow = inst.is_control_flow();
+        let next = self.pc.get();
+        inst.execute(self)?;
+        // stores below the guard need SP to get there first
+        if let Some(limit) = self.stack_guard
+            && self.regs.read(2) < limit
+        {
+            return Err(Error::StackOverflow(self.regs.read(2)));
+        }
+        if is_control_flow && let Some(pipeline) = &mut self.pipeline {
+            pipeline.resolve(self.pc.get() != next);
+        }
+        if let Some(code) = self.exit_request.take() {
+            return Ok(StepOutcome::Exit(code));
+        }
+        if let Some(addr) = self.watch_hit.take() {
+            return Ok(StepOutcome::Watchpoint(addr));
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    fn syscall(&mut self) -> Result<StepOutcome, Error> {
+        self.syscalls += 1;
+        if !sandbox::within(self.sandbox.max_syscalls, self.syscalls) {
+            return Err(Error::PolicyViolation(PolicyViolation::Syscalls));
+        }
+        // Each syscall evaluates to its return value, or a negated errno on failure.
+        let ret: i32 = match LinuxSyscall::from(self.regs.read(17)) {
+            // intercept exit syscall to check official risc-v test suite
+            LinuxSyscall::Exit | LinuxSyscall::ExitGroup => {
+                return Ok(StepOutcome::Exit(self.regs.read(10) as u8));
+            }
+            // write(fd, buf, count)
+            LinuxSyscall::Write => {
+                let (fd, buf, count) = (self.regs.read(10), self.regs.read(11), self.regs.read(12));
+                self.sys_write(fd, buf, count)?
+            }
+            // getrandom(buf, buflen, flags)
+            LinuxSyscall::GetRandom => {
+                let buf = self.regs.read(10);
+                let len = self.regs.read(11);
+                // stores like the guest's own, so protection and watchpoints apply
+                for i in 0..len {
+                    let byte = self.rng.next_u64() as u8;
+                    self.store(buf.wrapping_add(i), Size::Byte, byte as u32)?;
+                }
+                len as i32
+            }
+            // clock_gettime(clockid, tp)
+            // Every clock reports the same deterministic time.
+            LinuxSyscall::ClockGettime => {
+                let tp = self.regs.read(11);
+                let secs = self.cycles / self.frequency;
+                let nanos = (self.cycles % self.frequency) * 1_000_000_000 / self.frequency;
+                // struct timespec { int64_t tv_sec; long tv_nsec; } padded to 16 bytes
+                let timespec = [secs as u32, (secs >> 32) as u32, nanos as u32, 0];
+                for (i, word) in (0..).zip(timespec) {
+                    self.store(tp.wrapping_add(i * 4), Size::Word, word)?;
+                }
+                0
+            }
+            // close(fd)
+            // Only the standard streams exist and closing them is a no-op.
+            LinuxSyscall::Close => match self.regs.read(10) {
+                0..=2 => 0,
+                _ => -(EBADF as i32),
+            },
+            // read(fd, buf, count)
+            // There's no input, so stdin is always at end-of-file.
+            LinuxSyscall::Read => match self.regs.read(10) {
+                0 => 0,
+                _ => -(EBADF as i32),
+            },
+            // brk(addr)
+            // There's no heap, the break stays at 0, which is how a failed
+            // request to move it is reported.
+            LinuxSyscall::Brk => 0,
+            LinuxSyscall::Unknown(_) => -(ENOSYS as i32),
+        };
+        self.regs.write(10, ret as u32);
+        Ok(StepOutcome::Continue)
+    }
+
+    // Returns the number of bytes written, or a negated errno.
+    fn sys_write(&mut self, fd: u32, buf: u32, count: u32) -> Result<i32, Error> {
+        if fd != 1 && fd != 2 {
+            return Ok(-(EBADF as i32));
+        }
+        // `buf` is a virtual address, the whole buffer has to be
+        // readable before anything is written
+        let bytes = (0..count)
+            .map(|i| {
+                let addr = buf.checked_add(i).ok_or(Error::LoadAccessFault(buf, self.mem.len()))?;
+                Ok(self.load(addr, Size::Byte, true)? as u8)
+            })
+            .collect::<Result<Vec<u8>, Error>>();
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(Error::LoadAccessFault(..) | Error::LoadPageFault(_)) => {
+                return Ok(-(EFAULT as i32));
+            }
+            Err(e) => return Err(e),
+        };
+        if fd == 2 {
+            io::stderr().write_all(&bytes).map_err(Error::Io)?;
+            return Ok(count as i32);
+        }
+        // only what was actually written counts against the limit
+        let stdout_bytes = self.stdout_bytes + count as u64;
+        if !sandbox::within(self.sandbox.max_stdout_bytes, stdout_bytes) {
+            return Err(Error::PolicyViolation(PolicyViolation::StdoutBytes));
+        }
+        io::stdout().write_all(&bytes).map_err(Error::Io)?;
+        self.stdout_bytes = stdout_bytes;
+        Ok(count as i32)
+    }
+}
+
+// Reads the instruction at the virtual address `pc` (compressed ones zero-extended).
+// The halfwords are translated separately, as a 32-bit instruction may straddle two pages.
+fn read_translated(
+    mem: &Memory,
+    pc: u32,
+    mut translate: impl FnMut(u32) -> Result<u32, Error>,
+) -> Result<u32, Error> {
+    let addr = translate(pc)?;
+    if addr as usize + 2 > mem.len() {
+        return Err(Error::InvalidPC(addr, mem.len()));
+    }
+    let lo = mem.read(addr, Size::HalfWord, true)?;
+    if lo & 0b11 != 0b11 {
+        return Ok(lo);
+    }
+    let hi_addr = if pc % mmu::PAGE_SIZE == mmu::PAGE_SIZE - 2 {
+        translate(pc.wrapping_add(2))?
+    } else {
+        addr + 2
+    };
+    if hi_addr as usize + 2 > mem.len() {
+        return Err(Error::TruncatedInstruction(pc));
+    }
+    Ok(lo | (mem.read(hi_addr, Size::HalfWord, true)? << 16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{FRAMEBUFFER_ADDR, TEST_FINISHER_ADDR};
+
+    fn program(insts: &[u32]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn dump_state_to_writer() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(0x40);
+        cpu.regs.write(5, -3i32 as u32);
+        let mut out = Vec::new();
+        cpu.dump_state(7, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2 + 32);
+        assert_eq!(lines[..2], ["CPU dump at cycle 7", "PC: 0x40"]);
+        assert!(lines.contains(&"R5: -3"));
+    }
+
+    #[test]
+    fn dump_memory_hexdump() {
+        let mut cpu = Cpu::new(false);
+        for (i, b) in b"hello, world!\n\x00\xffRISC-V".iter().enumerate() {
+            cpu.mem.write(0x100 + i as u32, Size::Byte, *b as u32).unwrap();
+        }
+        let mut out = Vec::new();
+        cpu.dump_memory(0x100, 22, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00000100: 68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 ff | hello, world!...\n\
+             00000110: 52 49 53 43 2d 56                               | RISC-V\n"
+        );
+    }
+
+    #[test]
+    fn dump_memory_clamped() {
+        let cpu = Cpu::new(false);
+        let mut out = Vec::new();
+        cpu.dump_memory(MEM_SIZE as u32 - 4, 64, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 1);
+        let mut out = Vec::new();
+        cpu.dump_memory(u32::MAX, 64, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn register_accessors() {
+        let mut cpu = Cpu::new(false);
+        cpu.set_register(5, 42).unwrap();
+        cpu.set_register(0, 42).unwrap();
+        assert_eq!(cpu.get_register(5).unwrap(), 42);
+        assert_eq!(cpu.get_register(0).unwrap(), 0);
+        assert!(matches!(cpu.set_register(32, 1), Err(Error::InvalidRegister(32))));
+        assert!(matches!(cpu.get_register(32), Err(Error::InvalidRegister(32))));
+    }
+
+    #[test]
+    fn uninitialized_read_trap() {
+        let mut cpu = Cpu::new(false).with_uninitialized_read_trap();
+        let result = cpu.run(program(&[
+            // addi x5, x0, 42
+            0x02a00293,
+            // sw x5, 0x100(x0)
+            0x10502023,
+            // lw x6, 0x100(x0)
+            0x10002303,
+            // lw x7, 0x104(x0)
+            0x10402383,
+        ]));
+        assert!(matches!(result, Err(Error::UninitializedRead(0x104))));
+        assert_eq!(cpu.regs.read(6), 42);
+    }
+
+    #[test]
+    fn symbolize_address() {
+        let mut symbols = SymbolMap::new();
+        symbols.insert(0x1010, "main");
+        let cpu = Cpu::new(false).with_symbols(symbols);
+        assert_eq!(cpu.symbolize(0x1018), "0x1018 <main+0x8>");
+        assert_eq!(cpu.symbolize(0x100), "0x100");
+    }
+
+    #[test]
+    fn pipeline_load_use_stall() {
+        let mut cpu = Cpu::new(false).with_pipeline_model();
+        cpu.mem.load_program(program(&[
+            // lw x5, 0x100(x0)
+            0x10002283,
+            // add x6, x5, x5
+            0x00528333,
+            // lw x5, 0x100(x0)
+            0x10002283,
+            // add x6, x1, x1
+            0x00108333,
+        ]));
+        assert!(matches!(cpu.step_n(4), (4, Ok(StepOutcome::Continue))));
+        let stats = cpu.pipeline_stats().unwrap();
+        assert_eq!(stats.instructions, 4);
+        assert_eq!(stats.load_use_stalls, 1);
+        assert_eq!(stats.control_stalls, 0);
+    }
+
+    #[test]
+    fn histogram_counts_nops_separately() {
+        assert!(Cpu::new(false).histogram().is_none());
+        let mut cpu = Cpu::new(false).with_histogram();
+        // nop, addi x1, x1, 1, nop, c.nop, addi x0, x0, 1, add x2, x1, x1
+        for raw in [0x00000013, 0x00108093, 0x00000013, 0x0001, 0x00100013, 0x00108133] {
+            cpu.execute_raw(raw).unwrap();
+        }
+        let histogram = cpu.histogram().unwrap();
+        assert_eq!(histogram["nop"], 3);
+        assert_eq!(histogram["addi"], 2);
+        assert_eq!(histogram["add"], 1);
+    }
+
+    #[test]
+    fn pipeline_control_stalls() {
+        assert!(Cpu::new(false).pipeline_stats().is_none());
+        // beq x0, x0, 8 (taken), bne x0, x0, 8 (not taken)
+        let mut cpu = Cpu::new(false).with_pipeline_model();
+        cpu.execute_raw(0x00000463).unwrap();
+        cpu.execute_raw(0x00001463).unwrap();
+        assert_eq!(cpu.pipeline_stats().unwrap().control_stalls, 2);
+    }
+
+    #[test]
+    fn poisoned_registers() {
+        let cpu = Cpu::new(false).with_poisoned_registers();
+        assert_eq!(cpu.regs.read(0), 0);
+        assert_eq!(cpu.regs.read(1), POISON);
+        assert_eq!(cpu.regs.read(2), MEM_SIZE as u32);
+        assert_eq!(cpu.regs.read(31), POISON);
+    }
+
+    #[test]
+    fn with_memory_bounds() {
+        let mut cpu = Cpu::new(false)
+            .with_decode_cache()
+            .with_memory(Memory::from_buffer(vec![0; 0x1000].into_boxed_slice()));
+        assert_eq!(cpu.regs.read(2), 0x1000);
+        // lw x1, -4(x2) reads the last word
+        cpu.mem.write(0xffc, Size::Word, 7).unwrap();
+        cpu.execute_raw(0xffc12083).unwrap();
+        assert_eq!(cpu.regs.read(1), 7);
+        // lw x1, 0(x2) is past the end
+        assert!(matches!(
+            cpu.execute_raw(0x00012083),
+            Err(Error::LoadAccessFault(0x1000, 0x1000))
+        ));
+        // addi x0, x0, 0 in the last word still runs, the next fetch doesn't
+        cpu.mem.write(0xffc, Size::Word, 0x00000013).unwrap();
+        cpu.set_pc(0xffc).unwrap();
+        cpu.step().unwrap();
+        assert!(matches!(cpu.step(), Err(Error::InvalidPC(0x1000, 0x1000))));
+    }
+
+    #[test]
+    fn slli_max_shift_amount() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(2, 1);
+        // slli x1, x2, 31
+        cpu.execute_raw(0x01f11093).unwrap();
+        assert_eq!(cpu.regs.read(1), 0x80000000);
+    }
+
+    #[test]
+    fn shift_imm_reserved_upper_bits() {
+        let cpu = Cpu::new(false);
+        // slli x1, x2, 31 with imm[11:5] = 0b0000010
+        let result = cpu.decode(0x05f11093);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidInstFormat(FormatError::Shift(_)))
+        ));
+    }
+
+    #[test]
+    fn shift_imm_amount_over_31() {
+        let cpu = Cpu::new(false);
+        // slli/srli/srai x1, x2, 32: shamt[5] is set, which only RV64 allows
+        for raw_inst in [0x02011093, 0x02015093, 0x42015093] {
+            assert!(matches!(
+                cpu.decode(raw_inst),
+                Err(Error::InvalidInstFormat(FormatError::Shift(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_addi() {
+        let cpu = Cpu::new(false);
+        // addi x1, x2, -5
+        assert_eq!(
+            cpu.decode(0xffb10093).unwrap(),
+            Inst::I(
+                IInst::Arith(ArithIInst::ADDI),
+                IFormat {
+                    rd: 1,
+                    funct3: 0,
+                    rs1: 2,
+                    imm: -5i32 as u32,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn decode_info_add() {
+        let cpu = Cpu::new(false);
+        // add x1, x2, x3
+        let decoded = cpu.decode_info(0x003100b3).unwrap();
+        assert!(matches!(decoded.inst, Inst::R(RInst::ADD, _)));
+        assert_eq!(decoded.format, FormatKind::R);
+        assert_eq!(decoded.opcode, 0b0110011);
+        assert_eq!(
+            (decoded.rd, decoded.rs1, decoded.rs2, decoded.imm),
+            (Some(1), Some(2), Some(3), None)
+        );
+    }
+
+    #[test]
+    fn decode_all_mixed_lengths() {
+        let cpu = Cpu::new(false);
+        // c.nop, addi x1, x0, 5, upper half of a truncated instruction
+        let program = [0x01, 0x00, 0x93, 0x00, 0x50, 0x00, 0x13, 0x00];
+        let insts = cpu.decode_all(&program);
+        let addrs: Vec<u32> = insts.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(addrs, [0, 2, 6]);
+        assert!(matches!(insts[2].1, Err(Error::TruncatedInstruction(6))));
+    }
+
+    #[test]
+    fn decode_never_panics() {
+        let cpu = Cpu::new(false);
+        let mut rng = XorShift::new(1);
+        // every input must either decode or return an error
+        for raw_inst in [0, u32::MAX, 0x8000_0000, 0x7fff_ffff] {
+            let _ = cpu.decode(raw_inst);
+        }
+        for _ in 0..200_000 {
+            let _ = cpu.decode(rng.next_u64() as u32);
+        }
+        for raw_inst in 0..=u16::MAX {
+            let _ = cpu.decode_compressed(raw_inst);
+        }
+    }
+
+    #[test]
+    fn fetch_truncated_instruction() {
+        let mut cpu = Cpu::new(false);
+        let pc = MEM_SIZE as u32 - 2;
+        // lower half of `addi x0, x0, 0`
+        cpu.mem.write(pc, Size::HalfWord, 0x0013).unwrap();
+        cpu.pc.set(pc);
+        assert!(matches!(
+            cpu.emulate_cycle(),
+            Err(Error::TruncatedInstruction(addr)) if addr == pc
+        ));
+        assert_eq!(cpu.pc.get(), pc);
+    }
+
+    #[test]
+    fn peek_then_step() {
+        let mut cpu = Cpu::new(false).with_history(1);
+        cpu.mem.load_program(program(&[
+            // addi x1, x0, 5
+            0x00500093,
+            // invalid opcode
+            0xffffffff,
+        ]));
+        let inst = cpu.peek().unwrap();
+        assert_eq!(inst.to_string(), "li x1, 5");
+        // nothing ran
+        assert_eq!(cpu.pc.get(), 0);
+        assert_eq!(cpu.recent_instructions().count(), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.recent_instructions().collect::<Vec<_>>(), [(0, 0x00500093)]);
+        assert_eq!(cpu.regs.read(1), 5);
+
+        assert!(matches!(cpu.peek(), Err(Error::InvalidOpcode(_))));
+        assert_eq!(cpu.pc.get(), 4);
+    }
+
+    #[test]
+    fn fetch_pc_out_of_memory() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(u32::MAX - 1);
+        assert!(matches!(cpu.emulate_cycle(), Err(Error::InvalidPC(..))));
+    }
+
+    #[test]
+    fn compressed_nop() {
+        let mut cpu = Cpu::new(false);
+        // c.nop
+        // addi x1, x0, 5
+        let mut program = vec![0x01, 0x00];
+        program.extend(0x00500093u32.to_le_bytes());
+        cpu.mem.load_program(program);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc.get(), 2);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc.get(), 6);
+        assert_eq!(cpu.regs.read(1), 5);
+    }
+
+    #[test]
+    fn auipc_at_top_of_address_space() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(0xfffffffc);
+        // auipc x5, 0x1
+        cpu.execute_raw(0x00001297).unwrap();
+        assert_eq!(cpu.regs.read(5), 0xffc);
+        // the pc wraps past the end
+        assert_eq!(cpu.pc.get(), 0);
+    }
+
+    #[test]
+    fn jalr_clears_target_bit_0() {
+        for mut cpu in [Cpu::new(false), Cpu::new(false).without_compressed()] {
+            cpu.mem.load_program(program(&[
+                // addi x1, x0, 8
+                0x00800093,
+                // jalr x0, 1(x1)
+                0x00108067,
+                // addi a7, x0, 93
+                0x05d00893,
+                // ecall
+                0x00000073,
+            ]));
+            cpu.step().unwrap();
+            cpu.step().unwrap();
+            assert_eq!(cpu.pc.get(), 8);
+            assert_eq!(cpu.step_n(2).1.unwrap(), StepOutcome::Exit(0));
+        }
+    }
+
+    #[test]
+    fn execute_raw_addi() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(2, 40);
+        // addi x1, x2, 2
+        cpu.execute_raw(0x00210093).unwrap();
+        assert_eq!(cpu.regs.read(1), 42);
+        assert_eq!(cpu.pc.get(), 4);
+    }
+
+    #[test]
+    fn execute_raw_auipc() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(0x100);
+        // auipc x5, 0x1
+        cpu.execute_raw(0x00001297).unwrap();
+        assert_eq!(cpu.regs.read(5), 0x1100);
+    }
+
+    #[test]
+    fn lui_top_bit_set() {
+        let mut cpu = Cpu::new(false);
+        // lui x1, 0xfffff
+        cpu.execute_raw(0xfffff0b7).unwrap();
+        assert_eq!(cpu.regs.read(1), 0xfffff000);
+    }
+
+    #[test]
+    fn auipc_negative() {
+        let mut cpu = Cpu::new(false);
+        cpu.pc.set(0x1100);
+        // auipc x1, 0xfffff (pc - 0x1000)
+        cpu.execute_raw(0xfffff097).unwrap();
+        assert_eq!(cpu.regs.read(1), 0x100);
+    }
+
+    #[test]
+    fn memory_accessors() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(5, 0xcafe);
+        // sw x5, 0x40(x0)
+        cpu.execute_raw(0x04502023).unwrap();
+        assert_eq!(cpu.memory().read(0x40, Size::Word, true).unwrap(), 0xcafe);
+
+        cpu.memory_mut().write(0x40, Size::HalfWord, 0xbeef).unwrap();
+        assert_eq!(cpu.memory().read(0x40, Size::Word, true).unwrap(), 0xbeef);
+    }
+
+    #[test]
+    fn amoadd_decode() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x100, Size::Word, 5).unwrap();
+        cpu.regs.write(1, 0x100);
+        cpu.regs.write(2, 7);
+        // amoadd.w x3, x2, (x1)
+        cpu.execute_raw(0x0020a1af).unwrap();
+        assert_eq!(cpu.regs.read(3), 5);
+        assert_eq!(cpu.mem.read(0x100, Size::Word, true).unwrap(), 12);
+    }
+
+    #[test]
+    fn decode_fence() {
+        let cpu = Cpu::new(false);
+        // fence rw, rw
+        assert!(matches!(
+            cpu.decode(0x0330000f),
+            Ok(Inst::Fence(FenceInst::FENCE))
+        ));
+        // fence.tso
+        assert!(matches!(
+            cpu.decode(0x8330000f),
+            Ok(Inst::Fence(FenceInst::TSO))
+        ));
+        // fm = 0b1000 with pred = succ = W is reserved
+        assert!(matches!(
+            cpu.decode(0x8110000f),
+            Ok(Inst::Fence(FenceInst::FENCE))
+        ));
+    }
+
+    #[test]
+    fn overflow_warnings() {
+        let mut cpu = Cpu::new(false).with_overflow_warnings();
+        cpu.regs.write(2, i32::MAX as u32);
+        cpu.regs.write(3, 1);
+        // add x1, x2, x3
+        cpu.execute_raw(0x003100b3).unwrap();
+        assert_eq!(cpu.regs.read(1), i32::MIN as u32);
+        // addi x1, x2, -1
+        cpu.execute_raw(0xfff10093).unwrap();
+        assert_eq!(cpu.warnings(), [Warning::SignedOverflow(0)]);
+        // sub x1, x1, x3 (no overflow)
+        cpu.execute_raw(0x403080b3).unwrap();
+        // addi x1, x2, 1
+        cpu.execute_raw(0x00110093).unwrap();
+        assert_eq!(cpu.warnings(), [Warning::SignedOverflow(0), Warning::SignedOverflow(12)]);
+
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(2, i32::MAX as u32);
+        cpu.regs.write(3, 1);
+        cpu.execute_raw(0x003100b3).unwrap();
+        assert!(cpu.warnings().is_empty());
+    }
+
+    #[test]
+    fn zbb_count_bits() {
+        let mut cpu = Cpu::new(false).with_zbb();
+        cpu.regs.write(2, 0x0000ffff);
+        // clz x1, x2
+        cpu.execute_raw(0x60011093).unwrap();
+        assert_eq!(cpu.regs.read(1), 16);
+        cpu.regs.write(2, 0xff);
+        // cpop x1, x2
+        cpu.execute_raw(0x60211093).unwrap();
+        assert_eq!(cpu.regs.read(1), 8);
+        // rori x1, x2, 4
+        cpu.execute_raw(0x60415093).unwrap();
+        assert_eq!(cpu.regs.read(1), 0xf000000f);
+    }
+
+    #[test]
+    fn zbb_min_max() {
+        let mut cpu = Cpu::new(false).with_zbb();
+        cpu.regs.write(2, -1i32 as u32);
+        cpu.regs.write(3, 1);
+        // min x1, x2, x3
+        cpu.execute_raw(0x0a3140b3).unwrap();
+        assert_eq!(cpu.regs.read(1) as i32, -1);
+        // minu x1, x2, x3
+        cpu.execute_raw(0x0a3150b3).unwrap();
+        assert_eq!(cpu.regs.read(1), 1);
+    }
+
+    #[test]
+    fn zbb_disabled() {
+        let cpu = Cpu::new(false);
+        // clz x1, x2
+        assert!(matches!(
+            cpu.decode(0x60011093),
+            Err(Error::InvalidInstFormat(FormatError::Shift(_)))
+        ));
+        // andn x1, x2, x3
+        assert!(matches!(
+            cpu.decode(0x403170b3),
+            Err(Error::InvalidInstFormat(FormatError::R(_)))
+        ));
+    }
+
+    #[test]
+    fn decode_system() {
+        let cpu = Cpu::new(false);
+        assert_eq!(cpu.decode(0x00000073).unwrap(), Inst::SysCall(SysCall::Ecall));
+        assert_eq!(cpu.decode(0x00100073).unwrap(), Inst::SysCall(SysCall::Break));
+        assert_eq!(cpu.decode(0x30200073).unwrap(), Inst::SysCall(SysCall::Mret));
+        assert_eq!(cpu.decode(0x10200073).unwrap(), Inst::SysCall(SysCall::Sret));
+        assert_eq!(cpu.decode(0x10500073).unwrap(), Inst::SysCall(SysCall::Wfi));
+        // unknown funct12
+        assert!(matches!(
+            cpu.decode(0x00200073),
+            Err(Error::InvalidInstFormat(FormatError::I(_)))
+        ));
+        // csrrs x1, mstatus, x0
+        assert!(matches!(cpu.decode(0x300020f3), Ok(Inst::Csr(CsrInst::CSRRS, f)) if f.rd == 1));
+        // csrrwi x1, mscratch, 5
+        assert!(matches!(cpu.decode(0x3402d0f3), Ok(Inst::Csr(CsrInst::CSRRWI, f)) if f.rs1 == 5));
+        // sfence.vma x10, x11
+        assert!(matches!(
+            cpu.decode(0x12b50073),
+            Ok(Inst::SfenceVma(f)) if f.rs1 == 10 && f.rs2 == 11
+        ));
+    }
+
+    #[test]
+    fn unimp() {
+        let mut cpu = Cpu::new(false);
+        let err = cpu.execute_raw(UNIMP).unwrap_err();
+        assert!(matches!(err, Error::IllegalInstruction(UNIMP)));
+        assert!(format!("{err:?}").contains("unreachable"));
+        // c.unimp
+        assert!(matches!(cpu.decode_all(&[0, 0])[0].1, Err(Error::IllegalInstruction(0))));
+        // other CSR writes still decode
+        assert!(matches!(cpu.decode(0x30529073), Ok(Inst::Csr(CsrInst::CSRRW, _))));
+    }
+
+    #[test]
+    fn sv32_translation() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.load_program(program(&[
+            // lui x5, 0x80000; addi x5, x5, 0x10 (Sv32, root table at 0x10000)
+            0x800002b7, 0x01028293,
+            // csrw satp, x5
+            0x18029073,
+            // csrr x7, satp
+            0x180023f3,
+            // lui x6, 0x5
+            0x00005337,
+            // lbu a0, 4(x6)
+            0x00434503,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]));
+        // root entry points to the table at 0x11000, which identity maps
+        // the code page and maps 0x5000 to 0x3000 (V, R, W, X, A, D)
+        cpu.mem.write(0x10000, Size::Word, (0x11 << 10) | 0x1).unwrap();
+        cpu.mem.write(0x11000, Size::Word, 0xcf).unwrap();
+        cpu.mem.write(0x11000 + 5 * 4, Size::Word, (0x3 << 10) | 0xc7).unwrap();
+        cpu.mem.write(0x3004, Size::Byte, 42).unwrap();
+        assert!(matches!(cpu.emulate(), Ok(42)));
+        assert_eq!(cpu.regs.read(7), 0x80000010);
+        // the code page and the data page, everything else hits
+        assert_eq!(cpu.tlb().walks(), 2);
+        cpu.set_satp(cpu.satp());
+        cpu.load(0x5004, Size::Byte, true).unwrap();
+        assert_eq!(cpu.tlb().walks(), 3);
+        // sfence.vma x6 (0x5000) only flushes that page
+        cpu.load(0x0, Size::Byte, true).unwrap();
+        cpu.execute_raw(0x12030073).unwrap();
+        cpu.load(0x0, Size::Byte, true).unwrap();
+        assert_eq!(cpu.tlb().walks(), 4);
+        cpu.load(0x5004, Size::Byte, true).unwrap();
+        assert_eq!(cpu.tlb().walks(), 5);
+        // sfence.vma flushes everything
+        cpu.execute_raw(0x12000073).unwrap();
+        cpu.load(0x0, Size::Byte, true).unwrap();
+        assert_eq!(cpu.tlb().walks(), 6);
+        assert!(matches!(
+            cpu.load(0x6000, Size::Byte, true),
+            Err(Error::LoadPageFault(0x6000))
+        ));
+    }
+
+    #[test]
+    fn sv32_syscall_buffers() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.load_program(program(&[
+            // lui a0, 0x5 (mapped to 0x3000)
+            0x00005537,
+            // addi a1, x0, 16
+            0x01000593,
+            // addi a7, x0, 278 (getrandom)
+            0x11600893,
+            // ecall
+            0x00000073,
+        ]));
+        // identity maps the code page, 0x5000 maps to 0x3000 (V, R, W, X, A, D)
+        cpu.mem.write(0x10000, Size::Word, (0x11 << 10) | 0x1).unwrap();
+        cpu.mem.write(0x11000, Size::Word, 0xcf).unwrap();
+        cpu.mem.write(0x11000 + 5 * 4, Size::Word, (0x3 << 10) | 0xc7).unwrap();
+        cpu.set_satp(0x80000010);
+        // stops at the ecall, found through the translated PC
+        assert!(matches!(cpu.continue_until_syscall(), Ok(SyscallStop::Entry(e)) if e.pc == 12));
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs.read(10), 16);
+        assert!(cpu.mem.as_bytes()[0x3000..0x3010].iter().any(|&b| b != 0));
+        // nothing at the identical physical address
+        assert!(cpu.mem.as_bytes()[0x5000..0x5010].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn sv32_high_virtual_pc() {
+        let mut cpu = Cpu::new(false);
+        // addi a0, x0, 9; addi a7, x0, 93; ecall
+        cpu.mem.write_words(0x100, &[0x00900513, 0x05d00893, 0x00000073]).unwrap();
+        // 0xc0000000 is a megapage at physical address 0 (V, R, W, X, A, D)
+        cpu.mem.write(0x10000 + 0x300 * 4, Size::Word, 0xcf).unwrap();
+        cpu.set_satp(0x80000010);
+        cpu.set_pc(0xc0000100).unwrap();
+        assert!(matches!(cpu.peek(), Ok(Inst::I(..))));
+        assert!(matches!(cpu.emulate(), Ok(9)));
+        assert_eq!(cpu.pc.get(), 0xc000010c);
+
+        // An instruction at the very top of the address space whose upper half
+        // wraps around to 0: 0xfffff000 maps to 0x2000 and 0x0 to 0x3000.
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x10000 + 0x3ff * 4, Size::Word, (0x11 << 10) | 0x1).unwrap();
+        cpu.mem.write(0x11000 + 0x3ff * 4, Size::Word, (0x2 << 10) | 0xcf).unwrap();
+        cpu.mem.write(0x10000, Size::Word, (0x12 << 10) | 0x1).unwrap();
+        cpu.mem.write(0x12000, Size::Word, (0x3 << 10) | 0xcf).unwrap();
+        // addi a0, x0, 5, split over both pages
+        cpu.mem.write(0x2ffe, Size::HalfWord, 0x0513).unwrap();
+        cpu.mem.write(0x3000, Size::HalfWord, 0x0050).unwrap();
+        cpu.mem.write_words(0x3002, &[0x05d00893, 0x00000073]).unwrap();
+        cpu.set_satp(0x80000010);
+        cpu.set_pc(0xfffffffe).unwrap();
+        assert!(matches!(cpu.peek(), Ok(Inst::I(_, f)) if f.imm == 5));
+        assert!(matches!(cpu.emulate(), Ok(5)));
+    }
+
+    #[test]
+    fn execute_system() {
+        let mut cpu = Cpu::new(false);
+        // wfi
+        assert_eq!(cpu.execute_raw(0x10500073).unwrap(), StepOutcome::Continue);
+        // mret
+        assert!(matches!(cpu.execute_raw(0x30200073), Err(Error::Unsupported(_))));
+        // csrrs x1, mstatus, x0: unimplemented CSR
+        cpu.regs.write(1, 7);
+        assert!(matches!(cpu.execute_raw(0x300020f3), Err(Error::IllegalInstruction(0x300020f3))));
+        assert_eq!(cpu.regs.read(1), 7);
+    }
+
+    #[test]
+    fn counter_csrs() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.load_program(program(&[
+            // addi x0, x0, 0
+            0x00000013,
+            // csrrs x1, cycle, x0
+            0xc00020f3,
+            // csrrs x2, instreth, x0
+            0xc8202173,
+        ]));
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.regs.read(1), 2);
+        assert_eq!(cpu.regs.read(2), 0);
+        // csrrs x3, cycle, x1: a write to a read-only CSR
+        assert!(matches!(cpu.execute_raw(0xc000a1f3), Err(Error::IllegalInstruction(0xc000a1f3))));
+        assert_eq!(cpu.regs.read(3), 0);
+    }
+
+    #[test]
+    fn decode_pause() {
+        let mut cpu = Cpu::new(false);
+        assert_eq!(cpu.decode(0x0100000f).unwrap(), Inst::Fence(FenceInst::PAUSE));
+        // same pred/succ, but rd != x0 is a plain fence
+        assert_eq!(cpu.decode(0x0100008f).unwrap(), Inst::Fence(FenceInst::FENCE));
+        assert_eq!(cpu.execute_raw(0x0100000f).unwrap(), StepOutcome::Continue);
+    }
+
+    #[test]
+    fn decode_div() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(1, 42);
+        cpu.regs.write(2, 5);
+        // div x3, x1, x2
+        cpu.execute_raw(0x0220c1b3).unwrap();
+        assert_eq!(cpu.regs.read(3), 8);
+    }
+
+    #[test]
+    fn test_finisher_pass() {
+        let mut cpu = Cpu::new(false).with_test_finisher(TEST_FINISHER_ADDR);
+        let program = program(&[
+            // lui x5, 0x100
+            0x001002b7,
+            // lui x6, 0x5
+            0x00005337,
+            // addi x6, x6, 0x555
+            0x55530313,
+            // sw x6, 0(x5)
+            0x0062a023,
+        ]);
+        assert!(matches!(cpu.run(program), Ok(0)));
+    }
+
+    #[test]
+    fn test_finisher_fail() {
+        let mut cpu = Cpu::new(false).with_test_finisher(TEST_FINISHER_ADDR);
+        let program = program(&[
+            // lui x5, 0x100
+            0x001002b7,
+            // lui x6, 0x33
+            0x00033337,
+            // addi x6, x6, 0x333
+            0x33330313,
+            // sw x6, 0(x5)
+            0x0062a023,
+        ]);
+        assert!(matches!(cpu.run(program), Ok(3)));
+    }
+
+    #[test]
+    fn framebuffer_store() {
+        let mut cpu =
+            Cpu::new(false).with_framebuffer(FRAMEBUFFER_ADDR, 4, 2, PixelFormat::Rgbx8888);
+        assert_eq!(cpu.framebuffer().len(), 4 * 2 * 4);
+        // lui x5, 0x200
+        cpu.execute_raw(0x002002b7).unwrap();
+        // second pixel of the second row
+        cpu.regs.write(6, 0x00336699);
+        // sw x6, 20(x5)
+        cpu.execute_raw(0x0062aa23).unwrap();
+        assert_eq!(cpu.framebuffer()[20..24], [0x99, 0x66, 0x33, 0x00]);
+        assert!(cpu.framebuffer()[..20].iter().all(|&b| b == 0));
+        // RAM is untouched
+        assert_eq!(cpu.mem.read(20, Size::Word, true).unwrap(), 0);
+        // sw x6, 30(x5) runs past the end
+        assert!(matches!(
+            cpu.execute_raw(0x0062af23),
+            Err(Error::StoreAccessFault(0x20001e, _))
+        ));
+    }
+
+    #[test]
+    fn set_pc_alignment() {
+        let mut cpu = Cpu::new(false).without_compressed();
+        assert!(cpu.set_pc(0x100).is_ok());
+        assert_eq!(cpu.pc.get(), 0x100);
+        assert!(matches!(
+            cpu.set_pc(0x102),
+            Err(Error::InstructionAddressMisaligned(0x102))
+        ));
+        // unchanged
+        assert_eq!(cpu.pc.get(), 0x100);
+
+        // halfword alignment is enough with the C extension
+        let mut cpu = Cpu::new(false);
+        assert!(cpu.set_pc(0x102).is_ok());
+        assert!(cpu.set_pc(0x101).is_err());
+    }
+
+    #[test]
+    fn fetch_misaligned_pc() {
+        let mut cpu = Cpu::new(false).without_compressed();
+        // addi x0, x0, 0 at 0x100
+        cpu.mem.write(0x100, Size::Word, 0x00000013).unwrap();
+        // bypasses `set_pc`, like a corrupted PC would
+        cpu.pc.set(0x102);
+        assert!(matches!(cpu.step(), Err(Error::InstructionAddressMisaligned(0x102))));
+        // not fetched
+        assert_eq!(cpu.pc.get(), 0x102);
+        cpu.pc.set(0x100);
+        assert!(cpu.step().is_ok());
+    }
+
+    #[test]
+    fn compressed_disabled() {
+        let mut cpu = Cpu::new(false).without_compressed();
+        // c.addi x1, 1
+        let program = program(&[0x00010085]);
+        assert!(matches!(cpu.run(program), Err(Error::InvalidCompressedInst(0x0085))));
+    }
+
+    #[test]
+    fn stack_guard() {
+        let limit = MEM_SIZE as u32 - 0x100;
+        let mut cpu = Cpu::new(false).with_stack_guard(limit);
+        let program = program(&[
+            // addi sp, sp, -0x80
+            0xf8010113,
+            // addi sp, sp, -0x100
+            0xf0010113,
+        ]);
+        let sp = MEM_SIZE as u32 - 0x180;
+        assert!(matches!(cpu.run(program), Err(Error::StackOverflow(addr)) if addr == sp));
+        assert_eq!(cpu.pc.get(), 8);
+    }
+
+    #[test]
+    fn state_hash() {
+        let run = |imm: u32| {
+            let mut cpu = Cpu::new(false);
+            cpu.regs.write(5, imm);
+            // sw x5, 0x100(x0)
+            cpu.execute_raw(0x10502023).unwrap();
+            cpu
+        };
+        assert_eq!(run(1).state_hash(), run(1).state_hash());
+        assert_ne!(run(1).state_hash(), run(2).state_hash());
+        // only a register differs
+        let mut cpu = run(1);
+        cpu.regs.write(6, 1);
+        assert_ne!(cpu.state_hash(), run(1).state_hash());
+    }
+
+    #[test]
+    fn custom_decoder() {
+        // custom-0 with an R-type layout: rd = rs1 * rs2 + 1
+        let decoder: CustomDecoder = Box::new(|cpu, raw_inst| {
+            let f = RFormat::new(raw_inst);
+            if get_bits!(raw_inst, 0, 6) != 0b0001011 {
+                return Err(Error::InvalidOpcode(get_bits!(raw_inst, 0, 6)));
+            }
+            let val = cpu.regs.read(f.rs1) * cpu.regs.read(f.rs2) + 1;
+            cpu.regs.write(f.rd, val);
+            Ok(())
+        });
+        let mut cpu = Cpu::new(false).with_custom_decoder(decoder);
+        cpu.regs.write(2, 6);
+        cpu.regs.write(3, 7);
+        // custom-0 x1, x2, x3
+        cpu.execute_raw(0x0031008b).unwrap();
+        assert_eq!(cpu.regs.read(1), 43);
+        // custom-1 is rejected by the decoder
+        assert!(matches!(
+            cpu.execute_raw(0x0031002b),
+            Err(Error::InvalidOpcode(0b0101011))
+        ));
+
+        // illegal without a decoder
+        let cpu = Cpu::new(false);
+        assert!(matches!(cpu.decode(0x0031008b), Err(Error::InvalidOpcode(0b0001011))));
+    }
+
+    #[test]
+    fn memory_log_order() {
+        let mut cpu = Cpu::new(false);
+        cpu.enable_memory_log();
+        // addi x5, x0, 7
+        cpu.execute_raw(0x00700293).unwrap();
+        // sw x5, 0x100(x0)
+        cpu.execute_raw(0x10502023).unwrap();
+        // fence rw, rw
+        cpu.execute_raw(0x0330000f).unwrap();
+        // lw x6, 0x100(x0)
+        cpu.execute_raw(0x10002303).unwrap();
+        assert_eq!(
+            cpu.memory_log(),
+            [
+                MemEvent::Store { pc: 4, addr: 0x100, val: 7 },
+                MemEvent::Fence { pc: 8 },
+                MemEvent::Load { pc: 12, addr: 0x100, val: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn write_protect() {
+        let mut cpu = Cpu::new(false);
+        cpu.memory_write_protect(0x100..0x110);
+        let insts = [
+            // addi x5, x0, 1
+            0x00100293,
+            // sw x5, 0xfc(x0), right below the region
+            0x0e502e23,
+            // sh x5, 0x10e(x0)
+            0x10501723,
+        ];
+        cpu.mem.load_program(program(&insts));
+        cpu.step_n(2).1.unwrap();
+        assert!(matches!(cpu.step(), Err(Error::WriteToProtectedRegion(0x10e))));
+        assert_eq!(cpu.mem.read(0x10e, Size::HalfWord, true).unwrap(), 0);
+
+        cpu.memory_write_unprotect(0x100..0x110);
+        cpu.pc.set(8);
+        cpu.step().unwrap();
+        assert_eq!(cpu.mem.read(0x10e, Size::HalfWord, true).unwrap(), 1);
+    }
+
+    #[test]
+    fn write_protect_syscall() {
+        let mut cpu = Cpu::new(false);
+        cpu.memory_write_protect(0x104..0x108);
+        let result = cpu.run(program(&[
+            // addi a0, x0, 0x100
+            0x10000513,
+            // addi a1, x0, 8
+            0x00800593,
+            // addi a7, x0, 278 (getrandom)
+            0x11600893,
+            // ecall
+            0x00000073,
+        ]));
+        assert!(matches!(result, Err(Error::WriteToProtectedRegion(0x104))));
+        // the bytes before the region were filled, the region wasn't
+        assert_ne!(cpu.mem.read_words(0x100, 1).unwrap(), [0]);
+        assert_eq!(cpu.mem.read_words(0x104, 1).unwrap(), [0]);
+    }
+
+    #[test]
+    fn allowed_instructions() {
+        let exit = program(&[
+            // addi a0, x0, 7
+            0x00700513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        let mut cpu = Cpu::new(false).with_allowed_instructions(["addi", "ecall"]);
+        assert!(matches!(cpu.run(exit), Ok(7)));
+
+        let mut cpu = Cpu::new(false).with_allowed_instructions(["addi", "ecall"]);
+        let program = program(&[
+            // addi x1, x0, 1
+            0x00100093,
+            // add x3, x1, x2
+            0x002081b3,
+        ]);
+        assert!(matches!(
+            cpu.run(program),
+            Err(Error::DisallowedInstruction(mnemonic, 4)) if mnemonic == "add"
+        ));
+    }
+
+    #[test]
+    fn run_rejects_partial_programs() {
+        let mut cpu = Cpu::new(false);
+        assert!(matches!(cpu.run(vec![]), Err(Error::EmptyProgram)));
+        assert!(matches!(cpu.run(vec![0x13, 0x00, 0x00]), Err(Error::MisalignedProgram(3))));
+        assert_eq!(cpu.cycles, 0);
+        // c.nop is a whole instruction, but only with the C extension
+        let mut cpu = Cpu::new(false).without_compressed();
+        assert!(matches!(cpu.run(vec![0x01, 0x00]), Err(Error::MisalignedProgram(2))));
+    }
+
+    #[test]
+    fn run_to_completion_state() {
+        let mut cpu = Cpu::new(false);
+        let result = cpu.run_to_completion(loop_program()).unwrap();
+        assert_eq!(result.exit_code, 12);
+        assert_eq!(result.regs[1], 12);
+        assert_eq!(result.regs[17], 93);
+        // the pc is past the final ecall
+        assert_eq!(result.pc, 28);
+        assert_eq!(result.cycles, 2 + 12 * 2 + 3);
+        assert_eq!(result.mem.read(0, Size::Word, true).unwrap(), 0x00000093);
+    }
+
+    #[test]
+    fn exit_syscall() {
+        let mut cpu = Cpu::new(false);
+        // addi a0, x0, 7
+        // addi a7, x0, 93
+        // ecall
+        let program = program(&[0x00700513, 0x05d00893, 0x00000073]);
+        assert!(matches!(cpu.run(program), Ok(7)));
+    }
+
+    #[test]
+    fn clock_gettime_is_deterministic() {
+        // 10ns per instruction
+        let mut cpu = Cpu::new(false).with_frequency(100_000_000);
+        let program = program(&[
+            // addi a0, x0, 1 (CLOCK_MONOTONIC)
+            0x00100513,
+            // addi a1, x0, 0x100
+            0x10000593,
+            // addi a7, x0, 403
+            0x19300893,
+            // ecall
+            0x00000073,
+            // addi a1, x0, 0x110
+            0x11000593,
+            // addi x0, x0, 0
+            0x00000013,
+            // ecall
+            0x00000073,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        assert!(matches!(cpu.run(program), Ok(0)));
+        // the first call is the 4th instruction
+        assert_eq!(cpu.mem.read_words(0x100, 3).unwrap(), [0, 0, 40]);
+        // 3 instructions later
+        assert_eq!(cpu.mem.read_words(0x110, 3).unwrap(), [0, 0, 70]);
+    }
+
+    #[test]
+    fn write_return_value() {
+        let mut cpu = Cpu::new(false);
+        // "hi" at 0x100
+        cpu.mem.write(0x100, Size::HalfWord, 0x6968).unwrap();
+        cpu.regs.write(10, 2);
+        cpu.regs.write(11, 0x100);
+        cpu.regs.write(12, 2);
+        cpu.regs.write(17, 64);
+        // ecall
+        cpu.execute_raw(0x00000073).unwrap();
+        assert_eq!(cpu.regs.read(10), 2);
+
+        // bad file descriptor
+        cpu.regs.write(10, 5);
+        cpu.execute_raw(0x00000073).unwrap();
+        assert_eq!(cpu.regs.read(10) as i32, -(EBADF as i32));
+    }
+
+    #[test]
+    fn unimplemented_syscall_return_values() {
+        let mut cpu = Cpu::new(false);
+        let syscall = |cpu: &mut Cpu, number, a0| {
+            cpu.regs.write(10, a0);
+            cpu.regs.write(17, number);
+            // ecall
+            cpu.execute_raw(0x00000073).unwrap();
+            cpu.regs.read(10) as i32
+        };
+        assert_eq!(syscall(&mut cpu, 1234, 5), -(ENOSYS as i32));
+        // close
+        assert_eq!(syscall(&mut cpu, 57, 1), 0);
+        assert_eq!(syscall(&mut cpu, 57, 5), -(EBADF as i32));
+        // read
+        assert_eq!(syscall(&mut cpu, 63, 0), 0);
+        assert_eq!(syscall(&mut cpu, 63, 5), -(EBADF as i32));
+        // brk
+        assert_eq!(syscall(&mut cpu, 214, 0x1000), 0);
+    }
+
+    #[test]
+    fn write_outside_memory() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(10, 1);
+        // runs 2 bytes off the end of memory
+        cpu.regs.write(11, MEM_SIZE as u32 - 2);
+        cpu.regs.write(12, 4);
+        cpu.regs.write(17, 64);
+        // ecall
+        assert_eq!(cpu.execute_raw(0x00000073).unwrap(), StepOutcome::Continue);
+        assert_eq!(cpu.regs.read(10) as i32, -(EFAULT as i32));
+        assert_eq!(cpu.stdout_bytes, 0);
+        // wraps around the address space
+        cpu.regs.write(10, 2);
+        cpu.regs.write(11, u32::MAX);
+        cpu.execute_raw(0x00000073).unwrap();
+        assert_eq!(cpu.regs.read(10) as i32, -(EFAULT as i32));
+    }
+
+    #[test]
+    fn exit_group_syscall() {
+        let mut cpu = Cpu::new(false);
+        // addi a0, x0, 7
+        // addi a7, x0, 94
+        // ecall
+        let program = program(&[0x00700513, 0x05e00893, 0x00000073]);
+        assert!(matches!(cpu.run(program), Ok(7)));
+    }
+
+    #[test]
+    fn getrandom_is_deterministic() {
+        let mut cpu = Cpu::new(false).with_seed(42);
+        let program = program(&[
+            // addi a0, x0, 0x100
+            0x10000513,
+            // addi a1, x0, 8
+            0x00800593,
+            // addi a7, x0, 278
+            0x11600893,
+            // ecall
+            0x00000073,
+            // addi a0, x0, 0x108
+            0x10800513,
+            // ecall
+            0x00000073,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        // exit code is the byte count returned by the last getrandom
+        assert!(matches!(cpu.run(program), Ok(8)));
+        let bytes: Vec<u32> = (0x100..0x110)
+            .map(|addr| cpu.mem.read(addr, Size::Byte, true).unwrap())
+            .collect();
+        assert_eq!(
+            bytes,
+            [170, 191, 122, 104, 130, 35, 211, 50, 240, 37, 159, 220, 51, 85, 1, 239]
+        );
+    }
+
+    #[test]
+    fn history_keeps_last_instructions() {
+        let mut cpu = Cpu::new(false).with_history(3);
+        let program = program(&[
+            // addi x1, x0, 1
+            0x00100093,
+            0x00100093,
+            0x00100093,
+            0x00100093,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        cpu.run(program).unwrap();
+        let recent: Vec<_> = cpu.recent_instructions().collect();
+        assert_eq!(
+            recent,
+            [(0xc, 0x00100093), (0x10, 0x05d00893), (0x14, 0x00000073)]
+        );
+    }
+
+    #[test]
+    fn sandbox_stdout_limit() {
+        let policy = SandboxPolicy {
+            max_stdout_bytes: Some(4),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::new(false).with_sandbox(policy);
+        let mut program = program(&[
+            // addi a0, x0, 1
+            0x00100513,
+            // addi a1, x0, 0x100
+            0x10000593,
+            // addi a2, x0, 5
+            0x00500613,
+            // addi a7, x0, 64
+            0x04000893,
+            // ecall
+            0x00000073,
+        ]);
+        program.resize(0x100, 0);
+        // NUL-terminated, so the program is a whole number of halfwords
+        program.extend(b"hello\0");
+        assert!(matches!(
+            cpu.run(program),
+            Err(Error::PolicyViolation(PolicyViolation::StdoutBytes))
+        ));
+    }
+
+    #[test]
+    fn sandbox_syscall_limit() {
+        let policy = SandboxPolicy {
+            max_syscalls: Some(1),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::new(false).with_sandbox(policy);
+        let program = program(&[
+            // addi a7, x0, 278
+            0x11600893,
+            // ecall
+            0x00000073,
+            // ecall
+            0x00000073,
+        ]);
+        assert!(matches!(
+            cpu.run(program),
+            Err(Error::PolicyViolation(PolicyViolation::Syscalls))
+        ));
+    }
+
+    #[test]
+    fn sandbox_cycle_limit() {
+        let policy = SandboxPolicy {
+            max_cycles: Some(10),
+            ..Default::default()
+        };
+        let mut cpu = Cpu::new(false).with_sandbox(policy);
+        assert!(matches!(
+            cpu.run(loop_program()),
+            Err(Error::PolicyViolation(PolicyViolation::Cycles))
+        ));
+        assert_eq!(cpu.regs.read(1), 4);
+    }
+
+    #[test]
+    fn coverage_skips_branched_over() {
+        let mut cpu = Cpu::new(false).with_coverage();
+        let program = program(&[
+            // addi x1, x0, 1
+            0x00100093,
+            // beq x0, x0, 8
+            0x00000463,
+            // addi x1, x0, 2
+            0x00200093,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        cpu.run(program).unwrap();
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!(coverage.len(), 4);
+        assert!(coverage.contains(&0x4));
+        assert!(!coverage.contains(&0x8));
+        assert!(coverage.contains(&0x10));
+    }
+
+    #[test]
+    fn compressed_stack_round_trip() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(2, 0x1000);
+        cpu.regs.write(5, 0xdeadbeef);
+        // c.swsp x5, 0xe4(sp)
+        // c.lwsp x6, 0xe4(sp)
+        cpu.mem.load_program(vec![0x96, 0xd3, 0x1e, 0x53]);
+
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.mem.read(0x10e4, Size::Word, true).unwrap(), 0xdeadbeef);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.regs.read(6), 0xdeadbeef);
+        assert_eq!(cpu.pc.get(), 4);
+    }
+
+    fn exit_program(code: u32) -> Vec<u8> {
+        program(&[
+            // addi a0, x0, code
+            (code << 20) | 0x00000513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ])
+    }
+
+    fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("riscv-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_and_run_elf() {
+        let path = temp_file("exit.elf", &crate::elf::tests::elf(0x1000, &exit_program(3)));
+        let mut cpu = Cpu::new(false);
+        let result = cpu.load_and_run(&path);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Ok(3)));
+        assert_eq!(cpu.pc.get(), 0x100c);
+    }
+
+    #[test]
+    fn load_and_run_sets_global_pointer() {
+        use crate::elf::tests::{elf, with_symbol};
+        let bytes = with_symbol(elf(0x1000, &exit_program(0)), "__global_pointer$", 0x1800);
+        let path = temp_file("gp.elf", &bytes);
+        let mut cpu = Cpu::new(false);
+        let result = cpu.load_and_run(&path);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Ok(0)));
+        assert_eq!(cpu.regs.read(3), 0x1800);
+    }
+
+    #[test]
+    fn load_and_run_raw_binary() {
+        let path = temp_file("exit.bin", &exit_program(4));
+        let mut cpu = Cpu::new(false);
+        let result = cpu.load_and_run(&path);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Ok(4)));
+    }
+
+    #[test]
+    fn load_and_run_short_file() {
+        // too short to be ELF, runs as raw `c.ebreak`
+        let path = temp_file("short.bin", &[0x02, 0x90]);
+        let mut cpu = Cpu::new(false);
+        let result = cpu.load_and_run(&path);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Err(Error::Breakpoint(0))));
+    }
+
+    // counts x1 up to 12 in a loop and exits with it
+    fn loop_program() -> Vec<u8> {
+        program(&[
+            // addi x1, x0, 0
+            0x00000093,
+            // addi x2, x0, 12
+            0x00c00113,
+            // loop: addi x1, x1, 1
+            0x00108093,
+            // bne x1, x2, loop
+            0xfe209ee3,
+            // addi a0, x1, 0
+            0x00008513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ])
+    }
+
+    #[test]
+    fn decode_cache_matches_interpreter() {
+        let mut expected = Cpu::new(false);
+        assert!(matches!(expected.run(loop_program()), Ok(12)));
+        let mut cpu = Cpu::new(false).with_decode_cache();
+        assert!(matches!(cpu.run(loop_program()), Ok(12)));
+        assert_eq!(cpu.state_hash(), expected.state_hash());
+    }
+
+    // 65536 iterations of a few ALU ops and a store
+    fn hot_loop_program() -> Vec<u8> {
+        program(&[
+            // addi x1, x0, 0
+            0x00000093,
+            // lui x2, 0x10
+            0x00010137,
+            // loop: addi x1, x1, 1
+            0x00108093,
+            // xor x3, x3, x1
+            0x0011c1b3,
+            // add x4, x4, x3
+            0x00320233,
+            // sw x4, 0x400(x0)
+            0x40402023,
+            // bne x1, x2, loop
+            0xfe2098e3,
+            // andi a0, x4, 0xff
+            0x0ff27513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ])
+    }
+
+    #[test]
+    fn block_cache_matches_interpreter() {
+        let mut expected = Cpu::new(false);
+        assert!(matches!(expected.run(loop_program()), Ok(12)));
+        let mut cpu = Cpu::new(false).with_block_cache();
+        assert!(matches!(cpu.run(loop_program()), Ok(12)));
+        assert_eq!(cpu.state_hash(), expected.state_hash());
+        assert_eq!(cpu.cycles, expected.cycles);
+        // from 0 to the branch, the loop at 8, from 0x10 to the ecall
+        // and an empty one at the ecall, which is interpreted
+        assert_eq!(cpu.block_cache().unwrap().compiled(), 4);
+    }
+
+    #[test]
+    fn block_cache_sees_code_stores() {
+        let program = program(&[
+            // addi x6, x0, 2
+            0x00200313,
+            // lui x7, 0x208
+            0x002083b7,
+            // addi x7, x7, 0x93 (x7 = addi x1, x1, 2)
+            0x09338393,
+            // loop: addi x1, x1, 1
+            0x00108093,
+            // sw x7, 0xc(x0) overwrites the instruction above
+            0x00702623,
+            // addi x6, x6, -1
+            0xfff30313,
+            // bne x6, x0, loop
+            0xfe031ae3,
+            // addi a0, x1, 0
+            0x00008513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]);
+        let mut expected = Cpu::new(false);
+        assert!(matches!(expected.run(program.clone()), Ok(3)));
+        let mut cpu = Cpu::new(false).with_block_cache();
+        assert!(matches!(cpu.run(program), Ok(3)));
+        assert_eq!(cpu.state_hash(), expected.state_hash());
+    }
+
+    // Wall-clock timing isn't reliable with tests running in parallel,
+    // run it on its own: `cargo test --release block_cache_is_faster -- --ignored`
+    #[test]
+    #[ignore]
+    fn block_cache_is_faster() {
+        use std::time::{Duration, Instant};
+        // best of a few runs, so a busy machine doesn't decide it
+        let time = |make: fn() -> Cpu| -> (Duration, Cpu) {
+            let runs = (0..3).map(|_| {
+                let mut cpu = make();
+                let start = Instant::now();
+                cpu.run(hot_loop_program()).unwrap();
+                (start.elapsed(), cpu)
+            });
+            runs.min_by_key(|(elapsed, _)| *elapsed).unwrap()
+        };
+        let (plain, expected) = time(|| Cpu::new(false));
+        let (blocks, cpu) = time(|| Cpu::new(false).with_block_cache());
+        assert_eq!(cpu.state_hash(), expected.state_hash());
+        assert_eq!(cpu.cycles, expected.cycles);
+        assert!(blocks < plain, "block cache took {blocks:?}, the interpreter {plain:?}");
+    }
+
+    #[test]
+    fn decode_cache_sees_code_stores() {
+        let mut cpu = Cpu::new(false).with_decode_cache();
+        cpu.mem.load_program(program(&[
+            // addi x1, x1, 1
+            0x00108093,
+        ]));
+        cpu.step().unwrap();
+        // overwrite it with addi x1, x1, 2
+        cpu.store(0, Size::Word, 0x00208093).unwrap();
+        cpu.pc.set(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.regs.read(1), 3);
+    }
+
+    #[test]
+    fn step_n_matches_run() {
+        let mut expected = Cpu::new(false);
+        assert!(matches!(expected.run(loop_program()), Ok(12)));
+
+        let mut cpu = Cpu::new(false);
+        cpu.mem.load_program(loop_program());
+        let mut total = 0;
+        let outcome = loop {
+            let (ran, outcome) = cpu.step_n(5);
+            total += ran;
+            match outcome.unwrap() {
+                StepOutcome::Continue => assert_eq!(ran, 5),
+                outcome => break outcome,
+            }
+        };
+        assert_eq!(outcome, StepOutcome::Exit(12));
+        assert_eq!(total, 2 + 12 * 2 + 3);
+        assert_eq!(cpu.pc.get(), expected.pc.get());
+        for reg in 0..32 {
+            assert_eq!(cpu.regs.read(reg), expected.regs.read(reg));
+        }
+    }
+
+    #[test]
+    fn watchpoint_on_stack_store() {
+        let mut cpu = Cpu::new(false);
+        let slot = MEM_SIZE as u32 - 4;
+        cpu.add_watchpoint(slot, WatchKind::Write);
+        let result = cpu.run(program(&[
+            // addi sp, sp, -16
+            0xff010113,
+            // addi x5, x0, 42
+            0x02a00293,
+            // sw x5, 12(sp)
+            0x00512623,
+            // addi x5, x0, 0
+            0x00000293,
+        ]));
+        assert!(matches!(result, Err(Error::Watchpoint(addr)) if addr == slot));
+        // stops right after the store
+        assert_eq!(cpu.pc.get(), 12);
+        assert_eq!(cpu.mem.read(slot, Size::Word, true).unwrap(), 42);
+    }
+
+    #[test]
+    fn watchpoint_kind() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(2, 0x100);
+        cpu.add_watchpoint(0x10e, WatchKind::Read);
+        // sw x5, 12(sp)
+        assert_eq!(cpu.execute_raw(0x00512623).unwrap(), StepOutcome::Continue);
+        // lw x6, 12(sp) touches 0x10c..0x110
+        assert_eq!(
+            cpu.execute_raw(0x00c12303).unwrap(),
+            StepOutcome::Watchpoint(0x10e)
+        );
+        cpu.remove_watchpoint(0x10e);
+        assert_eq!(cpu.execute_raw(0x00c12303).unwrap(), StepOutcome::Continue);
+    }
+
+    #[test]
+    fn run_reader_matches_run() {
+        let mut expected = Cpu::new(false);
+        assert!(matches!(expected.run(loop_program()), Ok(12)));
+        let mut cpu = Cpu::new(false);
+        assert!(matches!(
+            cpu.run_reader(io::Cursor::new(loop_program())),
+            Ok(12)
+        ));
+        assert_eq!(cpu.regs.read(1), expected.regs.read(1));
+    }
+
+    #[test]
+    fn run_reader_too_large() {
+        let mut cpu = Cpu::new(false);
+        let result = cpu.run_reader(io::repeat(0x13));
+        assert!(matches!(result, Err(Error::ProgramTooLarge(MEM_SIZE))));
+    }
+
+    #[test]
+    fn cycle_hook_register_diff() {
+        let snapshots = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut cpu = Cpu::new(false);
+        let log = snapshots.clone();
+        cpu.on_cycle(Box::new(move |snapshot| {
+            let step = (snapshot.pc, snapshot.inst.mnemonic(), snapshot.changed.clone());
+            log.borrow_mut().push(step);
+        }));
+        cpu.run(program(&[
+            // addi x1, x0, 5
+            0x00500093,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]))
+        .unwrap();
+        assert_eq!(
+            *snapshots.borrow(),
+            [
+                (0, "addi".to_string(), vec![]),
+                (4, "addi".to_string(), vec![(1, 5)]),
+                (8, "ecall".to_string(), vec![(17, 93)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pc_hook_overrides_return_value() {
+        let mut cpu = Cpu::new(false);
+        cpu.on_pc(
+            12,
+            Box::new(|cpu| {
+                cpu.regs.write(10, 42);
+                // return straight to the caller
+                cpu.pc.set(cpu.regs.read(1));
+            }),
+        );
+        let result = cpu.run(program(&[
+            // jal ra, 12
+            0x00c000ef,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+            // addi a0, x0, 1 (never executed)
+            0x00100513,
+            // jalr x0, 0(ra)
+            0x00008067,
+        ]));
+        assert!(matches!(result, Ok(42)));
+    }
+
+    fn sum_program() -> Vec<u8> {
+        program(&[
+            // addi x1, x0, 1
+            0x00100093,
+            // addi x2, x0, 2
+            0x00200113,
+            // add x3, x1, x2
+            0x002081b3,
+        ])
+    }
+
+    #[test]
+    fn run_checked_matches() {
+        let reference = [
+            CommitRecord { pc: 0, write: Some((1, 1)) },
+            CommitRecord { pc: 4, write: Some((2, 2)) },
+            CommitRecord { pc: 8, write: Some((3, 3)) },
+        ];
+        let mut cpu = Cpu::new(false);
+        assert_eq!(cpu.run_checked(sum_program(), reference).unwrap(), None);
+    }
+
+    #[test]
+    fn run_checked_divergence() {
+        let reference = [
+            CommitRecord { pc: 0, write: Some((1, 1)) },
+            CommitRecord { pc: 4, write: Some((2, 2)) },
+            CommitRecord { pc: 8, write: Some((3, 4)) },
+        ];
+        let mut cpu = Cpu::new(false);
+        let divergence = cpu.run_checked(sum_program(), reference).unwrap().unwrap();
+        assert_eq!(divergence.expected.pc, 8);
+        assert_eq!(divergence.actual, CommitRecord { pc: 8, write: Some((3, 3)) });
+    }
+
+    #[test]
+    fn stop_signal_interrupts_run() {
+        let signal = Arc::new(AtomicBool::new(false));
+        let mut cpu = Cpu::new(false).with_stop_signal(signal.clone());
+        let setter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            signal.store(true, Ordering::Relaxed);
+        });
+        // jal x0, 0 (loops forever)
+        let result = cpu.run(program(&[0x0000006f]));
+        setter.join().unwrap();
+        assert!(matches!(result, Err(Error::Interrupted(0))));
+    }
+
+    #[test]
+    fn stop_signal_step() {
+        let signal = Arc::new(AtomicBool::new(true));
+        let mut cpu = Cpu::new(false).with_stop_signal(signal.clone());
+        cpu.mem.load_program(loop_program());
+        assert!(matches!(cpu.step_n(5), (0, Ok(StepOutcome::Interrupted))));
+        assert_eq!(cpu.pc.get(), 0);
+        // cleared once observed
+        assert!(!signal.load(Ordering::Relaxed));
+        assert!(matches!(cpu.step_n(5), (5, Ok(StepOutcome::Continue))));
+    }
+
+    // `Write` handle to a buffer that stays readable after being moved into the CPU
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let log = SharedBuf::default();
+        let mut cpu = Cpu::new(false);
+        cpu.record(log.clone());
+        assert!(matches!(cpu.run(loop_program()), Ok(12)));
+
+        let mut replayed = Cpu::new(false);
+        let log = log.0.borrow();
+        assert_eq!(log.len(), (2 + 12 * 2 + 3) * 4);
+        let outcome = replayed.replay(log.as_slice()).unwrap();
+        assert_eq!(outcome, StepOutcome::Exit(12));
+        assert_eq!(replayed.pc.get(), cpu.pc.get());
+        for reg in 0..32 {
+            assert_eq!(replayed.regs.read(reg), cpu.regs.read(reg));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_trace() {
+        let trace = SharedBuf::default();
+        let mut cpu = Cpu::new(false);
+        cpu.trace_json(trace.clone());
+        // addi x5, x0, 7
+        cpu.execute_raw(0x00700293).unwrap();
+        // sw x5, 0x100(x0)
+        cpu.execute_raw(0x10502023).unwrap();
+        let trace = String::from_utf8(trace.0.borrow().clone()).unwrap();
+        let lines: Vec<serde_json::Value> =
+            trace.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        // the real instruction, not the `li` pseudo-instruction
+        let addi = serde_json::json!({
+            "pc": 0, "inst": 7340691, "mnemonic": "addi", "rd": 5, "value": 7, "mem": null,
+        });
+        let sw = serde_json::json!({
+            "pc": 4, "inst": 273686563, "mnemonic": "sw", "rd": null, "value": null,
+            "mem": {"op": "store", "addr": 256, "value": 7},
+        });
+        assert_eq!(lines, [addi, sw]);
+    }
+
+    #[test]
+    fn continue_until_syscall_entries() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.load_program(program(&[
+            // addi a0, x0, 1
+            0x00100513,
+            // addi a7, x0, 64
+            0x04000893,
+            // ecall (write to stdout, 0 bytes)
+            0x00000073,
+            // addi a0, x0, 3
+            0x00300513,
+            // addi a7, x0, 93
+            0x05d00893,
+            // ecall
+            0x00000073,
+        ]));
+        let SyscallStop::Entry(write) = cpu.continue_until_syscall().unwrap() else {
+            panic!("expected a syscall");
+        };
+        assert_eq!((write.pc, write.number, write.args[0]), (8, 64, 1));
+        let SyscallStop::Entry(exit) = cpu.continue_until_syscall().unwrap() else {
+            panic!("expected a syscall");
+        };
+        assert_eq!((exit.pc, exit.number, exit.args[0]), (20, 93, 3));
+        assert_eq!(
+            cpu.continue_until_syscall().unwrap(),
+            SyscallStop::Done(StepOutcome::Exit(3))
+        );
+    }
+
+    #[test]
+    fn continue_until_syscall_without_syscall() {
+        let mut cpu = Cpu::new(false);
+        // c.ebreak
+        cpu.mem.load_program(vec![0x02, 0x90]);
+        assert_eq!(
+            cpu.continue_until_syscall().unwrap(),
+            SyscallStop::Done(StepOutcome::Breakpoint(0))
+        );
+    }
+
+    #[test]
+    fn step_n_stops_on_fault() {
+        let mut cpu = Cpu::new(false);
+        // addi x1, x0, 1
+        // invalid instruction
+        cpu.mem.load_program(program(&[0x00100093, 0xffffffff]));
+        let (ran, outcome) = cpu.step_n(5);
+        assert_eq!(ran, 1);
+        assert!(matches!(outcome, Err(Error::InvalidOpcode(_))));
+    }
+
+    #[test]
+    fn compressed_ebreak() {
+        let mut cpu = Cpu::new(false);
+        // c.nop
+        // c.ebreak
+        let result = cpu.run(vec![0x01, 0x00, 0x02, 0x90]);
+        assert!(matches!(result, Err(Error::Breakpoint(2))));
     }
 }
@@ -0,0 +1,96 @@
+// Memory-mapped devices living outside of RAM.
+
+// Conventional address of the SiFive test finisher (as in QEMU's `virt` machine).
+pub const TEST_FINISHER_ADDR: u32 = 0x100000;
+
+// SiFive test finisher.
+// Bare-metal test suites end the run by storing a word to it:
+// `0x5555` means pass, `0x3333 | code << 16` means fail with `code`.
+pub struct TestFinisher {
+    pub addr: u32,
+}
+
+impl TestFinisher {
+    pub fn new(addr: u32) -> Self {
+        TestFinisher { addr }
+    }
+
+    // Decodes a stored value into an exit code,
+    // unknown values are ignored.
+    pub fn exit_code(&self, val: u32) -> Option<u8> {
+        match val & 0xffff {
+            0x5555 => Some(0),
+            0x3333 => Some((val >> 16) as u8),
+            _ => None,
+        }
+    }
+}
+
+// Conventional address of the framebuffer, right after the test finisher.
+pub const FRAMEBUFFER_ADDR: u32 = 0x200000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    // 3 bytes per pixel: red, green, blue
+    Rgb888,
+    // 4 bytes per pixel: red, green, blue and an ignored padding byte,
+    // so every pixel can be written with a single `sw`
+    Rgbx8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgbx8888 => 4,
+        }
+    }
+}
+
+// Linear framebuffer mapped at `addr`. Pixels are stored row by row
+// starting at the top left corner, the host reads them with `Cpu::framebuffer`.
+pub struct Framebuffer {
+    pub addr: u32,
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(addr: u32, width: usize, height: usize, format: PixelFormat) -> Self {
+        Framebuffer {
+            addr,
+            width,
+            height,
+            format,
+            pixels: vec![0; width * height * format.bytes_per_pixel()],
+        }
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // Whether a `len`-byte access at `addr` touches the framebuffer.
+    pub fn contains(&self, addr: u32, len: usize) -> bool {
+        let end = self.addr as usize + self.pixels.len();
+        (addr as usize) < end && addr as usize + len > self.addr as usize
+    }
+
+    // Writes the low `len` bytes of `val` (little-endian like memory).
+    // The whole access has to fit, partially mapped stores are rejected.
+    pub fn write(&mut self, addr: u32, len: usize, val: u32) -> bool {
+        let Some(offset) = addr.checked_sub(self.addr) else {
+            return false;
+        };
+        let offset = offset as usize;
+        match self.pixels.get_mut(offset..offset + len) {
+            Some(dst) => {
+                dst.copy_from_slice(&val.to_le_bytes()[..len]);
+                true
+            }
+            None => false,
+        }
+    }
+}
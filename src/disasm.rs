@@ -0,0 +1,75 @@
+// Linear-sweep disassembler printing a listing like `objdump -d`.
+use crate::cpu::Cpu;
+use std::io::{self, Write};
+
+// Writes one `address: raw  instruction` line per instruction.
+// Invalid instructions and a trailing partial instruction are printed
+// as `.word` with their raw bytes.
+pub fn disassemble(program: &[u8], writer: &mut impl Write) -> io::Result<()> {
+    let cpu = Cpu::new(false).with_zbb();
+    let mut end = 0;
+    for (addr, inst) in cpu.decode_all(program) {
+        let start = addr as usize;
+        let len = if program[start] & 0b11 != 0b11 { 2 } else { 4 };
+        end = program.len().min(start + len);
+        let raw = raw_hex(&program[start..end]);
+        match inst {
+            Ok(inst) => writeln!(writer, "{addr:8x}: {raw:<8}  {inst}")?,
+            Err(_) => writeln!(writer, "{addr:8x}: {raw:<8}  .word 0x{raw}")?,
+        }
+    }
+    // a single byte is too short for `decode_all` to report
+    if end < program.len() {
+        let raw = raw_hex(&program[end..]);
+        writeln!(writer, "{end:8x}: {raw:<8}  .word 0x{raw}")?;
+    }
+    Ok(())
+}
+
+// little-endian bytes as a single hex number
+fn raw_hex(bytes: &[u8]) -> String {
+    bytes.iter().rev().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(program: &[u8]) -> Vec<String> {
+        let mut out = Vec::new();
+        disassemble(program, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn listing_lines() {
+        let words: [u32; 3] = [
+            // addi x0, x0, 0
+            0x00000013,
+            // add x1, x2, x3
+            0x003100b3,
+            // jalr x0, 0(x1)
+            0x00008067,
+        ];
+        let program: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(
+            listing(&program),
+            [
+                "       0: 00000013  nop",
+                "       4: 003100b3  add x1, x2, x3",
+                "       8: 00008067  ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_and_truncated() {
+        // invalid opcode, then the first half of a 32-bit instruction
+        let program = [0xff, 0xff, 0xff, 0xff, 0x13, 0x00];
+        assert_eq!(
+            listing(&program),
+            ["       0: ffffffff  .word 0xffffffff", "       4: 0013      .word 0x0013"]
+        );
+        assert_eq!(listing(&[0x13]), ["       0: 13        .word 0x13"]);
+    }
+}
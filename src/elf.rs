@@ -0,0 +1,339 @@
+// Minimal loader for 32-bit little-endian RISC-V ELF executables.
+// Only `PT_LOAD` segments are loaded and, for position-independent
+// executables, `R_RISCV_RELATIVE` relocations applied. Everything else is ignored.
+use crate::error::Error;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const SHT_SYMTAB: u32 = 2;
+const DT_NULL: u32 = 0;
+const DT_RELA: u32 = 7;
+const DT_RELASZ: u32 = 8;
+const DT_RELAENT: u32 = 9;
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_RELATIVE: u32 = 3;
+
+// Position-independent executables are linked at address 0
+// and moved here, so that null pointers still fault.
+pub const PIE_LOAD_BASE: u32 = 0x1000;
+
+pub struct Elf {
+    // address of the first instruction
+    pub entry: u32,
+    // memory contents with all segments in place
+    pub image: Vec<u8>,
+    // value of `__global_pointer$`, which startup code puts in `gp`
+    pub global_pointer: Option<u32>,
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, Error> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(Error::InvalidElf("file is truncated"))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(Error::InvalidElf("file is truncated"))
+}
+
+impl Elf {
+    // `mem_size` is the size of the image, every segment has to fit in it.
+    pub fn parse(bytes: &[u8], mem_size: usize) -> Result<Self, Error> {
+        if !is_elf(bytes) {
+            return Err(Error::InvalidElf("missing ELF magic"));
+        }
+        if bytes.get(4) != Some(&ELFCLASS32) || bytes.get(5) != Some(&ELFDATA2LSB) {
+            return Err(Error::InvalidElf("not a 32-bit little-endian ELF"));
+        }
+        if read_u16(bytes, 0x12)? != EM_RISCV {
+            return Err(Error::InvalidElf("not a RISC-V ELF"));
+        }
+        // load bias, zero for non-PIE executables
+        let base = if read_u16(bytes, 0x10)? == ET_DYN {
+            PIE_LOAD_BASE
+        } else {
+            0
+        };
+        let entry = read_u32(bytes, 0x18)?.wrapping_add(base);
+        let ph_off = read_u32(bytes, 0x1c)? as usize;
+        let ph_size = read_u16(bytes, 0x2a)? as usize;
+        let ph_num = read_u16(bytes, 0x2c)? as usize;
+
+        let mut image = vec![0; mem_size];
+        let mut dynamic = None;
+        for i in 0..ph_num {
+            // 32-bit program header:
+            // p_type, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags, p_align
+            let ph = ph_off + i * ph_size;
+            let p_type = read_u32(bytes, ph)?;
+            if p_type == PT_DYNAMIC {
+                dynamic = Some(read_u32(bytes, ph + 0x8)?.wrapping_add(base));
+            }
+            if p_type != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(bytes, ph + 0x4)? as usize;
+            let vaddr = read_u32(bytes, ph + 0x8)?.wrapping_add(base) as usize;
+            let file_size = read_u32(bytes, ph + 0x10)? as usize;
+            let seg_size = read_u32(bytes, ph + 0x14)? as usize;
+            if file_size > seg_size || vaddr + seg_size > mem_size {
+                return Err(Error::InvalidElf("segment doesn't fit in memory"));
+            }
+            let data = bytes
+                .get(offset..offset + file_size)
+                .ok_or(Error::InvalidElf("file is truncated"))?;
+            // the rest of the segment (.bss) is already zeroed
+            image[vaddr..vaddr + file_size].copy_from_slice(data);
+        }
+        // static executables have no dynamic section
+        if let Some(dynamic) = dynamic {
+            relocate(&mut image, base, dynamic)?;
+        }
+        let global_pointer =
+            find_symbol(bytes, "__global_pointer$")?.map(|gp| gp.wrapping_add(base));
+        Ok(Elf {
+            entry,
+            image,
+            global_pointer,
+        })
+    }
+}
+
+// Looks up the value of a symbol in the symbol table, if the file has one.
+fn find_symbol(bytes: &[u8], name: &str) -> Result<Option<u32>, Error> {
+    let sh_off = read_u32(bytes, 0x20)? as usize;
+    let sh_size = read_u16(bytes, 0x2e)? as usize;
+    let sh_num = read_u16(bytes, 0x30)? as usize;
+    for i in 0..sh_num {
+        // 32-bit section header:
+        // sh_name, sh_type, sh_flags, sh_addr, sh_offset, sh_size, sh_link, ...
+        let sh = sh_off + i * sh_size;
+        if read_u32(bytes, sh + 0x4)? != SHT_SYMTAB {
+            continue;
+        }
+        let sym_off = read_u32(bytes, sh + 0x10)? as usize;
+        let sym_count = read_u32(bytes, sh + 0x14)? as usize / 16;
+        // string table with the symbol names
+        let str_sh = sh_off + read_u32(bytes, sh + 0x18)? as usize * sh_size;
+        let str_off = read_u32(bytes, str_sh + 0x10)? as usize;
+        for j in 0..sym_count {
+            // st_name, st_value, st_size, st_info, st_other, st_shndx
+            let sym = sym_off + j * 16;
+            let name_off = str_off + read_u32(bytes, sym)? as usize;
+            let sym_name = bytes
+                .get(name_off..)
+                .and_then(|b| b.split(|&c| c == 0).next())
+                .ok_or(Error::InvalidElf("file is truncated"))?;
+            if sym_name == name.as_bytes() {
+                return Ok(Some(read_u32(bytes, sym + 0x4)?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn read_image_u32(image: &[u8], addr: u32) -> Result<u32, Error> {
+    read_u32(image, addr as usize).map_err(|_| Error::InvalidElf("address outside of memory"))
+}
+
+// `addr + offset`, addresses come from the file and may be anything.
+fn image_addr(addr: u32, offset: u32) -> Result<u32, Error> {
+    addr.checked_add(offset).ok_or(Error::InvalidElf("address outside of memory"))
+}
+
+// Applies the `.rela.dyn` relocations listed in the dynamic section at `dynamic`.
+// Only `R_RISCV_RELATIVE` is supported: *(base + offset) = base + addend.
+fn relocate(image: &mut [u8], base: u32, dynamic: u32) -> Result<(), Error> {
+    let (mut rela, mut rela_size, mut rela_ent) = (0, 0, 12);
+    // d_tag, d_val pairs terminated by DT_NULL
+    let mut entry = dynamic;
+    loop {
+        let val = read_image_u32(image, image_addr(entry, 4)?)?;
+        match read_image_u32(image, entry)? {
+            DT_NULL => break,
+            DT_RELA => rela = val,
+            DT_RELASZ => rela_size = val,
+            DT_RELAENT => rela_ent = val,
+            _ => (),
+        }
+        entry = image_addr(entry, 8)?;
+    }
+    if rela_ent == 0 {
+        return Err(Error::InvalidElf("invalid relocation entry size"));
+    }
+    let rela = rela.wrapping_add(base);
+    for i in 0..rela_size / rela_ent {
+        // r_offset, r_info, r_addend
+        let rel = i
+            .checked_mul(rela_ent)
+            .and_then(|off| rela.checked_add(off))
+            .ok_or(Error::InvalidElf("address outside of memory"))?;
+        let offset = read_image_u32(image, rel)?.wrapping_add(base);
+        let addend = read_image_u32(image, image_addr(rel, 8)?)?;
+        match read_image_u32(image, image_addr(rel, 4)?)? & 0xff {
+            R_RISCV_NONE => (),
+            R_RISCV_RELATIVE => {
+                read_image_u32(image, offset)?;
+                let offset = offset as usize;
+                image[offset..offset + 4].copy_from_slice(&base.wrapping_add(addend).to_le_bytes());
+            }
+            _ => return Err(Error::InvalidElf("unsupported dynamic relocation")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::memory::MEM_SIZE;
+
+    // Builds an executable with a single segment holding `code` at `vaddr`.
+    pub fn elf(vaddr: u32, code: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; 52 + 32];
+        bytes[..4].copy_from_slice(&ELF_MAGIC);
+        bytes[4] = ELFCLASS32;
+        bytes[5] = ELFDATA2LSB;
+        // e_type = ET_EXEC
+        bytes[0x10..0x12].copy_from_slice(&2u16.to_le_bytes());
+        bytes[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        bytes[0x18..0x1c].copy_from_slice(&vaddr.to_le_bytes());
+        bytes[0x1c..0x20].copy_from_slice(&52u32.to_le_bytes());
+        bytes[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+        bytes[0x2c..0x2e].copy_from_slice(&1u16.to_le_bytes());
+        let ph = 52;
+        bytes[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        bytes[ph + 0x4..ph + 0x8].copy_from_slice(&84u32.to_le_bytes());
+        bytes[ph + 0x8..ph + 0xc].copy_from_slice(&vaddr.to_le_bytes());
+        bytes[ph + 0x10..ph + 0x14].copy_from_slice(&(code.len() as u32).to_le_bytes());
+        bytes[ph + 0x14..ph + 0x18].copy_from_slice(&(code.len() as u32 + 16).to_le_bytes());
+        bytes.extend_from_slice(code);
+        bytes
+    }
+
+    // Appends a symbol table with a single `name = value` symbol.
+    pub fn with_symbol(mut bytes: Vec<u8>, name: &str, value: u32) -> Vec<u8> {
+        let str_off = bytes.len() as u32;
+        let strtab = [&[0], name.as_bytes(), &[0]].concat();
+        bytes.extend_from_slice(&strtab);
+        let sym_off = bytes.len() as u32;
+        // null symbol and `name`
+        bytes.extend_from_slice(&[0; 16]);
+        for word in [1, value, 0, 0] {
+            bytes.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        let sh_off = bytes.len() as u32;
+        // null section, .symtab linked to .strtab
+        bytes.extend_from_slice(&[0; 40]);
+        for word in [0, SHT_SYMTAB, 0, 0, sym_off, 32, 2, 1, 4, 16] {
+            bytes.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        // SHT_STRTAB
+        for word in [0, 3, 0, 0, str_off, strtab.len() as u32, 0, 0, 1, 0] {
+            bytes.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        bytes[0x20..0x24].copy_from_slice(&sh_off.to_le_bytes());
+        bytes[0x2e..0x30].copy_from_slice(&40u16.to_le_bytes());
+        bytes[0x30..0x32].copy_from_slice(&3u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parse_global_pointer() {
+        let bytes = with_symbol(elf(0x1000, &[1, 2, 3, 4]), "__global_pointer$", 0x1800);
+        assert_eq!(Elf::parse(&bytes, MEM_SIZE).unwrap().global_pointer, Some(0x1800));
+        let bytes = with_symbol(elf(0x1000, &[1, 2, 3, 4]), "_start", 0x1000);
+        assert_eq!(Elf::parse(&bytes, MEM_SIZE).unwrap().global_pointer, None);
+        let elf = Elf::parse(&elf(0x1000, &[1, 2, 3, 4]), MEM_SIZE).unwrap();
+        assert_eq!(elf.global_pointer, None);
+    }
+
+    #[test]
+    fn parse_segment() {
+        let elf = Elf::parse(&elf(0x1000, &[1, 2, 3, 4]), MEM_SIZE).unwrap();
+        assert_eq!(elf.entry, 0x1000);
+        assert_eq!(elf.image[0x1000..0x1006], [1, 2, 3, 4, 0, 0]);
+    }
+
+    // Position-independent executable mapping the whole file at 0.
+    // It has a pointer at `0xa0` which must point to the word at `0xa4`.
+    fn pie() -> Vec<u8> {
+        let mut bytes = vec![0; 168];
+        bytes[..4].copy_from_slice(&ELF_MAGIC);
+        bytes[4] = ELFCLASS32;
+        bytes[5] = ELFDATA2LSB;
+        bytes[0x10..0x12].copy_from_slice(&ET_DYN.to_le_bytes());
+        bytes[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        bytes[0x1c..0x20].copy_from_slice(&52u32.to_le_bytes());
+        bytes[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+        bytes[0x2c..0x2e].copy_from_slice(&2u16.to_le_bytes());
+        let words = |bytes: &mut Vec<u8>, at: usize, words: &[u32]| {
+            for (i, word) in words.iter().enumerate() {
+                bytes[at + i * 4..at + i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+            }
+        };
+        // PT_LOAD of the whole file
+        words(&mut bytes, 52, &[PT_LOAD, 0, 0, 0, 168, 168]);
+        // PT_DYNAMIC at 0x74
+        words(&mut bytes, 84, &[PT_DYNAMIC, 0x74, 0x74, 0x74, 32, 32]);
+        words(&mut bytes, 0x74, &[DT_RELA, 0x94, DT_RELASZ, 12, DT_RELAENT, 12, DT_NULL, 0]);
+        words(&mut bytes, 0x94, &[0xa0, R_RISCV_RELATIVE, 0xa4]);
+        words(&mut bytes, 0xa4, &[0xdeadbeef]);
+        bytes
+    }
+
+    #[test]
+    fn parse_pie_relocated() {
+        let elf = Elf::parse(&pie(), MEM_SIZE).unwrap();
+        assert_eq!(elf.entry, PIE_LOAD_BASE);
+        let ptr = read_u32(&elf.image, PIE_LOAD_BASE as usize + 0xa0).unwrap();
+        assert_eq!(ptr, PIE_LOAD_BASE + 0xa4);
+        assert_eq!(read_u32(&elf.image, ptr as usize).unwrap(), 0xdeadbeef);
+    }
+
+    #[test]
+    fn parse_pie_near_top_of_address_space() {
+        // PT_DYNAMIC relocated to 0xfffffffc, its first d_val would be past 4 GiB
+        let mut bytes = pie();
+        bytes[92..96].copy_from_slice(&(0xfffffffc - PIE_LOAD_BASE).to_le_bytes());
+        assert!(matches!(
+            Elf::parse(&bytes, MEM_SIZE),
+            Err(Error::InvalidElf("address outside of memory"))
+        ));
+    }
+
+    #[test]
+    fn parse_unsupported_relocation() {
+        let mut bytes = pie();
+        // R_RISCV_32
+        bytes[0x98] = 1;
+        assert!(matches!(
+            Elf::parse(&bytes, MEM_SIZE),
+            Err(Error::InvalidElf("unsupported dynamic relocation"))
+        ));
+    }
+
+    #[test]
+    fn parse_wrong_machine() {
+        let mut bytes = elf(0x1000, &[1, 2, 3, 4]);
+        bytes[0x12] = 0x3e;
+        assert!(matches!(
+            Elf::parse(&bytes, MEM_SIZE),
+            Err(Error::InvalidElf("not a RISC-V ELF"))
+        ));
+    }
+}
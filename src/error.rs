@@ -1,16 +1,50 @@
+use crate::get_bits;
+use crate::inst::UNIMP;
 use crate::inst_format::{BFormat, IFormat, RFormat, SFormat};
+use crate::sandbox::PolicyViolation;
 use std::fmt::{Debug, Formatter};
 
 pub enum Error {
     InvalidOpcode(usize),
+    InvalidCompressedInst(u16),
     InvalidInstFormat(FormatError),
     InvalidPC(u32, usize),
+    TruncatedInstruction(u32),
+    InvalidRegister(usize),
+    IllegalInstruction(u32),
+    InstructionAddressMisaligned(u32),
+    LoadAddressMisaligned(u32),
+    StoreAddressMisaligned(u32),
+    // address and memory size
+    LoadAccessFault(u32, usize),
+    StoreAccessFault(u32, usize),
+    InstructionPageFault(u32),
+    LoadPageFault(u32),
+    StorePageFault(u32),
     EndOfInstructions,
+    DivideByZero(u32),
+    PolicyViolation(PolicyViolation),
+    InvalidElf(&'static str),
+    Unsupported(&'static str),
+    Io(std::io::Error),
+    Breakpoint(u32),
+    Watchpoint(u32),
+    ProgramTooLarge(usize),
+    EmptyProgram,
+    // program size, not a multiple of the instruction alignment
+    MisalignedProgram(usize),
+    Interrupted(u32),
+    UninitializedRead(u32),
+    StackOverflow(u32),
+    WriteToProtectedRegion(u32),
+    DisallowedInstruction(String, u32),
 }
 
 pub enum FormatError {
     R(RFormat),
     I(IFormat),
+    // shift-immediate with reserved imm[11:5]
+    Shift(IFormat),
     S(SFormat),
     B(BFormat),
 }
@@ -22,6 +56,8 @@ impl Debug for Error {
             "{}",
             match self {
                 Error::InvalidOpcode(opcode) => format!("invalid opcode: {:07b}", opcode),
+                Error::InvalidCompressedInst(raw_inst) =>
+                    format!("invalid compressed instruction: {:016b}", raw_inst),
                 Error::InvalidInstFormat(kind) => match kind {
                     FormatError::R(format) => format!(
                         "invalid R-format instruction: funct3: '{:03b}', funct7: '{:07b}'",
@@ -31,6 +67,10 @@ impl Debug for Error {
                         "invalid I-format instruction: funct3: '{:03b}'",
                         format.funct3
                     ),
+                    FormatError::Shift(format) => format!(
+                        "invalid shift-immediate instruction: funct3: '{:03b}', imm[11:5]: '{:07b}' is reserved",
+                        format.funct3, get_bits!(format.imm, 5, 11)
+                    ),
                     FormatError::S(format) => format!(
                         "invalid S-format instruction: funct3: '{:03b}'",
                         format.funct3
@@ -42,9 +82,65 @@ impl Debug for Error {
                 },
                 Error::InvalidPC(pc, mem_size) =>
                     format!("program counter (pc: {pc}) bigger than memory (mem_size: {mem_size}B)"),
+                Error::TruncatedInstruction(pc) =>
+                    format!("instruction at the end of memory is truncated (pc: {pc})"),
+                Error::InvalidRegister(reg) =>
+                    format!("invalid register: x{reg} (rvi32 has only 32 registers)"),
+                // mcause 2
+                Error::IllegalInstruction(raw_inst @ (UNIMP | 0x0000)) => format!(
+                    "illegal instruction: unimp ({raw_inst:#x}), compilers emit it \
+                     for code that should be unreachable"
+                ),
+                Error::IllegalInstruction(raw_inst) =>
+                    format!("illegal instruction: {raw_inst:#010x}"),
+                // mcause 0
+                Error::InstructionAddressMisaligned(addr) =>
+                    format!("instruction address misaligned (addr: {addr:#x})"),
+                // mcause 4
+                Error::LoadAddressMisaligned(addr) =>
+                    format!("load address misaligned (addr: {addr:#x})"),
+                // mcause 6
+                Error::StoreAddressMisaligned(addr) =>
+                    format!("store address misaligned (addr: {addr:#x})"),
+                // mcause 5
+                Error::LoadAccessFault(addr, mem_size) =>
+                    format!("load access fault (addr: {addr:#x}, mem_size: {mem_size}B)"),
+                // mcause 7
+                Error::StoreAccessFault(addr, mem_size) =>
+                    format!("store access fault (addr: {addr:#x}, mem_size: {mem_size}B)"),
+                // mcause 12
+                Error::InstructionPageFault(addr) =>
+                    format!("instruction page fault (addr: {addr:#x})"),
+                // mcause 13
+                Error::LoadPageFault(addr) => format!("load page fault (addr: {addr:#x})"),
+                // mcause 15
+                Error::StorePageFault(addr) => format!("store page fault (addr: {addr:#x})"),
                 Error::EndOfInstructions =>
                     "program ran out of instructions! Use exit syscall to terminate gracefully."
                         .to_string(),
+                Error::DivideByZero(pc) => format!("division by zero (pc: {pc})"),
+                Error::PolicyViolation(kind) => format!("sandbox policy violated: {kind:?} limit exceeded"),
+                Error::InvalidElf(reason) => format!("invalid ELF file: {reason}"),
+                Error::Unsupported(what) => format!("unsupported: {what}"),
+                Error::Io(err) => format!("I/O error: {err}"),
+                Error::Breakpoint(pc) => format!("breakpoint hit (pc: {pc})"),
+                Error::Watchpoint(addr) => format!("watchpoint hit (addr: {addr:#x})"),
+                Error::UninitializedRead(addr) =>
+                    format!("read of uninitialized memory (addr: {addr:#x})"),
+                Error::DisallowedInstruction(mnemonic, pc) =>
+                    format!("instruction not in the allowlist: {mnemonic} (pc: {pc:#x})"),
+                Error::WriteToProtectedRegion(addr) =>
+                    format!("store to write-protected memory (addr: {addr:#x})"),
+                Error::StackOverflow(sp) =>
+                    format!("stack pointer below the stack guard (sp: {sp:#x})"),
+                Error::Interrupted(pc) => format!("interrupted by the host (pc: {pc})"),
+                Error::EmptyProgram => "program is empty, there's nothing to run".to_string(),
+                Error::MisalignedProgram(len) => format!(
+                    "program size ({len}B) isn't a multiple of the instruction size, \
+                     is it a raw binary?"
+                ),
+                Error::ProgramTooLarge(mem_size) =>
+                    format!("program doesn't fit in memory (mem_size: {mem_size}B)"),
             }
         )
     }
@@ -1,10 +1,13 @@
 // https://projectf.io/posts/riscv-cheat-sheet/
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, Warning};
 use crate::get_bits;
 use crate::inst_format::*;
-use crate::memory::{Memory, Size};
+use crate::error::Error;
+use crate::memory::{MisalignedAccess, Size};
+use std::fmt::{Debug, Display, Formatter};
 use std::ops::{BitAnd, BitOr, BitXor};
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Inst {
     // register-register operations
     R(RInst, RFormat),
@@ -18,16 +21,81 @@ pub enum Inst {
     J(JFormat),
     // upper immediate instructions
     U(UInst, UFormat),
+    // atomic memory operations (A extension)
+    Amo(AmoInst, RFormat),
+
+    // fence instructions
+    Fence(FenceInst),
 
     // This isn't an official instruction but just
     // so that the emulator doesn't crash on `ecall`.
-    // Only handles exit for now, every other syscall is ignored.
     SysCall(SysCall),
+    // Zicsr, the CSR number is imm[11:0]
+    Csr(CsrInst, IFormat),
+    // `sfence.vma rs1, rs2` flushes cached translations of the virtual
+    // address in rs1 and the ASID in rs2, x0 meaning all of them.
+    SfenceVma(RFormat),
+
+    // instruction from the custom opcode space,
+    // executed by the CPU's `CustomDecoder`
+    Custom(u32),
 }
 
+// `unimp`, which is `csrrw x0, cycle, x0`: a write to a read-only CSR.
+// Its compressed form is the all-zero halfword.
+pub const UNIMP: u32 = 0xc0001073;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum SysCall {
-    Exit(u8),
-    Nop,
+    // `ecall`, dispatched by the syscall number in `a7`
+    // (see `LinuxSyscall`).
+    Ecall,
+    // `ebreak` hands control over to the debugger.
+    Break,
+    // Return from an M-mode/S-mode trap handler.
+    // There are no traps yet, so executing them is an error.
+    Mret,
+    Sret,
+    // Wait For Interrupt, a hint that is allowed to just continue.
+    Wfi,
+}
+
+// CSR instructions atomically read the old value into rd and update the CSR.
+// The `I` variants use the rs1 field as a 5-bit zero-extended immediate.
+// Implemented are `satp` and the read-only `cycle`/`instret` counters
+// (see `Cpu::csr_read`). Accessing any other CSR, or writing a read-only one
+// (csr[11:10] == 0b11), raises `IllegalInstruction`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsrInst {
+    // csr = rs1
+    CSRRW,
+    // csr |= rs1
+    CSRRS,
+    // csr &= !rs1
+    CSRRC,
+    CSRRWI,
+    CSRRSI,
+    CSRRCI,
+}
+
+impl CsrInst {
+    fn is_imm(&self) -> bool {
+        matches!(self, CsrInst::CSRRWI | CsrInst::CSRRSI | CsrInst::CSRRCI)
+    }
+}
+
+// Memory ordering instructions. There's only one hart,
+// so they execute as no-ops.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FenceInst {
+    // Orders accesses from the predecessor set (imm[7:4])
+    // before accesses from the successor set (imm[3:0]).
+    FENCE,
+    // Total Store Ordering fence: fm = 0b1000, pred = succ = RW.
+    TSO,
+    // Zihintpause: `fence w, 0` hint telling the hart
+    // it's in a spin loop and may yield.
+    PAUSE,
 }
 
 // 0x1F = 0b00011111 = 31.
@@ -35,6 +103,7 @@ pub enum SysCall {
 // 5 bits of rs2 are used for shifting,
 // because shifting a 32-bit value by ≥32 bits is
 // meaningless (shifting by 32 would clear all bits).
+#[derive(Clone, Debug, PartialEq)]
 pub enum RInst {
     // Addition
     // Format: ADD rd, rs1, rs2.
@@ -86,9 +155,126 @@ pub enum RInst {
     // Operation: rd = (rs1 < rs2) ? 1 : 0 (unsigned comparison).
     // Description: Sets rd to 1 if rs1 < rs2 (treating values as unsigned).
     SLTU,
+
+    // M extension (integer multiplication and division)
+
+    // Multiply
+    // Format: MUL rd, rs1, rs2.
+    // Operation: rd = (rs1 * rs2)[31:0].
+    // Description: Keeps the lower 32 bits of the product.
+    MUL,
+    // Multiply High
+    // Format: MULH rd, rs1, rs2.
+    // Operation: rd = (rs1 * rs2)[63:32] (both signed).
+    MULH,
+    // Multiply High Signed-Unsigned
+    // Format: MULHSU rd, rs1, rs2.
+    // Operation: rd = (rs1 * rs2)[63:32] (rs1 signed, rs2 unsigned).
+    MULHSU,
+    // Multiply High Unsigned
+    // Format: MULHU rd, rs1, rs2.
+    // Operation: rd = (rs1 * rs2)[63:32] (both unsigned).
+    MULHU,
+    // Divide
+    // Format: DIV rd, rs1, rs2.
+    // Operation: rd = rs1 / rs2 (signed, rounds towards zero).
+    // Description: Division by zero doesn't trap, it gives -1.
+    // Overflow (-2^31 / -1) gives -2^31.
+    DIV,
+    // Divide Unsigned
+    // Format: DIVU rd, rs1, rs2.
+    // Operation: rd = rs1 / rs2 (unsigned).
+    // Description: Division by zero gives 2^32 - 1 (all bits set).
+    DIVU,
+    // Remainder
+    // Format: REM rd, rs1, rs2.
+    // Operation: rd = rs1 % rs2 (signed, sign of the dividend).
+    // Description: Division by zero gives rs1, overflow gives 0.
+    REM,
+    // Remainder Unsigned
+    // Format: REMU rd, rs1, rs2.
+    // Operation: rd = rs1 % rs2 (unsigned).
+    // Description: Division by zero gives rs1.
+    REMU,
+
+    // Zbb extension (basic bit manipulation), decoded only if enabled
+
+    // AND with inverted operand
+    // Format: ANDN rd, rs1, rs2.
+    // Operation: rd = rs1 & !rs2.
+    ANDN,
+    // OR with inverted operand
+    // Format: ORN rd, rs1, rs2.
+    // Operation: rd = rs1 | !rs2.
+    ORN,
+    // Exclusive NOR
+    // Format: XNOR rd, rs1, rs2.
+    // Operation: rd = !(rs1 ^ rs2).
+    XNOR,
+    // Minimum
+    // Format: MIN rd, rs1, rs2.
+    // Operation: rd = min(rs1, rs2) (signed comparison).
+    MIN,
+    // Maximum
+    // Format: MAX rd, rs1, rs2.
+    // Operation: rd = max(rs1, rs2) (signed comparison).
+    MAX,
+    // Minimum Unsigned
+    // Format: MINU rd, rs1, rs2.
+    // Operation: rd = min(rs1, rs2) (unsigned comparison).
+    MINU,
+    // Maximum Unsigned
+    // Format: MAXU rd, rs1, rs2.
+    // Operation: rd = max(rs1, rs2) (unsigned comparison).
+    MAXU,
+    // Rotate Left
+    // Format: ROL rd, rs1, rs2.
+    // Operation: rd = rs1 rotated left by (rs2 & 0x1F).
+    // Description: Bits shifted out on the left come back on the right.
+    ROL,
+    // Rotate Right (also RORI)
+    // Format: ROR rd, rs1, rs2.
+    // Operation: rd = rs1 rotated right by (rs2 & 0x1F).
+    ROR,
+    // The following take a single operand, rs2 is ignored.
+
+    // Count Leading Zeros
+    // Format: CLZ rd, rs1.
+    // Operation: rd = number of 0 bits before the most significant 1 (32 for 0).
+    CLZ,
+    // Count Trailing Zeros
+    // Format: CTZ rd, rs1.
+    // Operation: rd = number of 0 bits after the least significant 1 (32 for 0).
+    CTZ,
+    // Count Population
+    // Format: CPOP rd, rs1.
+    // Operation: rd = number of 1 bits in rs1.
+    CPOP,
+    // Sign-extend Byte
+    // Format: SEXT.B rd, rs1.
+    // Operation: rd = sign-extended rs1[7:0].
+    SEXTB,
+    // Sign-extend Halfword
+    // Format: SEXT.H rd, rs1.
+    // Operation: rd = sign-extended rs1[15:0].
+    SEXTH,
 }
 
 impl RInst {
+    fn is_div(&self) -> bool {
+        matches!(self, RInst::DIV | RInst::DIVU | RInst::REM | RInst::REMU)
+    }
+
+    // Whether ADD/SUB of the operands overflows when treated as signed.
+    // The result still wraps as the spec requires, this is only for diagnostics.
+    fn signed_overflow(&self, rs1: u32, rs2: u32) -> bool {
+        match self {
+            RInst::ADD => (rs1 as i32).overflowing_add(rs2 as i32).1,
+            RInst::SUB => (rs1 as i32).overflowing_sub(rs2 as i32).1,
+            _ => false,
+        }
+    }
+
     fn op(self) -> impl FnOnce(u32, u32) -> u32 {
         match self {
             RInst::ADD => u32::wrapping_add,
@@ -107,11 +293,49 @@ impl RInst {
                 rs1 >> amount
             },
             RInst::SRA => |rs1, rs2| {
-                let amount = get_bits!(rs2, 0, 4, i32);
+                let amount = get_bits!(rs2, 0, 4, u32);
+                // shifting a signed value copies the sign bit in
                 (rs1 as i32 >> amount) as u32
             },
             RInst::SLT => |rs1, rs2| ((rs1 as i32) < (rs2 as i32)) as u32,
             RInst::SLTU => |rs1, rs2| (rs1 < rs2) as u32,
+            RInst::MUL => u32::wrapping_mul,
+            RInst::MULH => |rs1, rs2| {
+                let product = rs1 as i32 as i64 * rs2 as i32 as i64;
+                (product >> 32) as u32
+            },
+            RInst::MULHSU => |rs1, rs2| {
+                let product = rs1 as i32 as i64 * rs2 as i64;
+                (product >> 32) as u32
+            },
+            RInst::MULHU => |rs1, rs2| {
+                let product = rs1 as u64 * rs2 as u64;
+                (product >> 32) as u32
+            },
+            RInst::DIV => |rs1, rs2| match rs2 {
+                0 => u32::MAX,
+                _ => (rs1 as i32).wrapping_div(rs2 as i32) as u32,
+            },
+            RInst::DIVU => |rs1: u32, rs2| rs1.checked_div(rs2).unwrap_or(u32::MAX),
+            RInst::REM => |rs1, rs2| match rs2 {
+                0 => rs1,
+                _ => (rs1 as i32).wrapping_rem(rs2 as i32) as u32,
+            },
+            RInst::REMU => |rs1: u32, rs2| rs1.checked_rem(rs2).unwrap_or(rs1),
+            RInst::ANDN => |rs1: u32, rs2: u32| rs1 & !rs2,
+            RInst::ORN => |rs1: u32, rs2: u32| rs1 | !rs2,
+            RInst::XNOR => |rs1: u32, rs2: u32| !(rs1 ^ rs2),
+            RInst::MIN => |rs1, rs2| (rs1 as i32).min(rs2 as i32) as u32,
+            RInst::MAX => |rs1, rs2| (rs1 as i32).max(rs2 as i32) as u32,
+            RInst::MINU => u32::min,
+            RInst::MAXU => u32::max,
+            RInst::ROL => |rs1: u32, rs2| rs1.rotate_left(get_bits!(rs2, 0, 4, u32)),
+            RInst::ROR => |rs1: u32, rs2| rs1.rotate_right(get_bits!(rs2, 0, 4, u32)),
+            RInst::CLZ => |rs1: u32, _| rs1.leading_zeros(),
+            RInst::CTZ => |rs1: u32, _| rs1.trailing_zeros(),
+            RInst::CPOP => |rs1: u32, _| rs1.count_ones(),
+            RInst::SEXTB => |rs1, _| rs1 as i8 as i32 as u32,
+            RInst::SEXTH => |rs1, _| rs1 as i16 as i32 as u32,
         }
     }
 }
@@ -128,12 +352,19 @@ impl From<ArithIInst> for RInst {
             ArithIInst::SRAI => RInst::SRA,
             ArithIInst::SLTI => RInst::SLT,
             ArithIInst::SLTIU => RInst::SLTU,
+            ArithIInst::RORI => RInst::ROR,
+            ArithIInst::CLZ => RInst::CLZ,
+            ArithIInst::CTZ => RInst::CTZ,
+            ArithIInst::CPOP => RInst::CPOP,
+            ArithIInst::SEXTB => RInst::SEXTB,
+            ArithIInst::SEXTH => RInst::SEXTH,
         }
     }
 }
 
 // the same as `RInst`, but instead of `rs2` `imm` is used.
 // `I` at the end of an instruction stands for `immediate`.
+#[derive(Clone, Debug, PartialEq)]
 pub enum ArithIInst {
     ADDI,
     XORI,
@@ -144,8 +375,17 @@ pub enum ArithIInst {
     SRAI,
     SLTI,
     SLTIU,
+    // Zbb, the single-operand ones are encoded as
+    // shift-immediates with imm[11:5] = 0x30.
+    RORI,
+    CLZ,
+    CTZ,
+    CPOP,
+    SEXTB,
+    SEXTH,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum LoadIInst {
     // Load Byte
     // Format: LB rd, offset (rs1).
@@ -192,17 +432,21 @@ impl LoadIInst {
         matches!(self, LoadIInst::LBU | LoadIInst::LHU)
     }
 
-    fn op(self, mem: &Memory) -> impl FnOnce(u32, u32) -> u32 + '_ {
+    fn op(self, cpu: &mut Cpu) -> impl FnOnce(u32, u32) -> Result<u32, Error> + '_ {
         move |rs1, imm| {
             // TODO: why do we use an offset here?
             let from = u32::wrapping_add(rs1, imm);
             let is_unsigned = self.is_unsigned();
             let size = Size::from(self);
-            mem.read(from, size, is_unsigned)
+            if cpu.misaligned_access() == MisalignedAccess::Trap && !size.is_aligned(from) {
+                return Err(Error::LoadAddressMisaligned(from));
+            }
+            cpu.load(from, size, is_unsigned)
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum IInst {
     Arith(ArithIInst),
     Mem(LoadIInst),
@@ -216,16 +460,20 @@ pub enum IInst {
 
 impl IInst {
     // TODO: why is the return type boxed?
-    fn op(self, cpu: &mut Cpu) -> Box<dyn FnOnce(u32, u32) -> u32 + '_> {
+    fn op(self, cpu: &mut Cpu) -> Box<dyn FnOnce(u32, u32) -> Result<u32, Error> + '_> {
         // Arithmetic operations are the same for R/I format,
         // only the second operand differs.
         match self {
-            IInst::Arith(inst) => Box::new(RInst::from(inst).op()),
-            IInst::Mem(inst) => Box::new(inst.op(&cpu.mem)),
+            IInst::Arith(inst) => {
+                let op = RInst::from(inst).op();
+                Box::new(|rs1, imm| Ok(op(rs1, imm)))
+            }
+            IInst::Mem(inst) => Box::new(inst.op(cpu)),
             IInst::Jalr => Box::new(|rs1, imm| {
                 let original_pc = cpu.pc.get();
-                cpu.pc.set(u32::wrapping_add(rs1, imm));
-                original_pc
+                // the lowest bit of the target is cleared
+                cpu.pc.set(u32::wrapping_add(rs1, imm) & !1);
+                Ok(original_pc)
             }),
         }
     }
@@ -234,6 +482,7 @@ impl IInst {
 // sw  # mem[rs1+imm] = rs2             ; store word
 // sh  # mem[rs1+imm][0:15] = rs2[0:15] ; store half word
 // sb  # mem[rs1+imm][0:7] = rs2[0:7]   ; store byte
+#[derive(Clone, Debug, PartialEq)]
 pub enum SInst {
     // Store Byte
     SB,
@@ -244,11 +493,14 @@ pub enum SInst {
 }
 
 impl SInst {
-    fn op(self, mem: &mut Memory) -> impl FnOnce(u32, u32, u32) + '_ {
+    fn op(self, cpu: &mut Cpu) -> impl FnOnce(u32, u32, u32) -> Result<(), Error> + '_ {
         move |rs1, rs2, imm| {
             let from = u32::wrapping_add(rs1, imm);
             let size = Size::from(self);
-            mem.write(from, size, rs2)
+            if cpu.misaligned_access() == MisalignedAccess::Trap && !size.is_aligned(from) {
+                return Err(Error::StoreAddressMisaligned(from));
+            }
+            cpu.store(from, size, rs2)
         }
     }
 }
@@ -261,6 +513,7 @@ impl SInst {
 // BLTU	 Branch if Less Than (Unsigned)	        rs1 < rs2 (unsigned)	Unsigned
 // BGE	 Branch if Greater or Equal	            rs1 >= rs2 (signed)	    Signed
 // BGEU	 Branch if Greater or Equal (Unsigned)  rs1 >= rs2 (unsigned)   Unsigned
+#[derive(Clone, Debug, PartialEq)]
 pub enum BInst {
     BEQ,
     BNE,
@@ -270,6 +523,7 @@ pub enum BInst {
     BGEU,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum UInst {
     // Load Upper Immediate
     // Loads a 20-bit immediate value into the upper 20 bits
@@ -287,20 +541,403 @@ pub enum UInst {
 
 impl UInst {
     fn op(self, pc: u32) -> impl FnOnce(u32) -> u32 {
-        // TODO: what does it do?
+        // `UFormat` keeps imm[31:12] in the low 20 bits (sign-extended),
+        // so shifting it back by 12 puts it into place and zeroes the lower 12 bits.
+        // The sign-extended top bits are shifted out, so an immediate with
+        // its top bit set, e.g. `0xfffff`, results in `0xfffff000`.
+        // AUIPC adds that to the address of the instruction itself,
+        // which `pc` is (see `ProgramCounter::current`).
         move |imm| match self {
             UInst::LUI => imm << 12,
-            UInst::AUIPC => u32::wrapping_add(pc - 4, imm << 12),
+            UInst::AUIPC => u32::wrapping_add(pc, imm << 12),
+        }
+    }
+}
+
+// Atomic Memory Operations (RV32A)
+// Format: AMOADD.W rd, rs2, (rs1).
+// Operation: rd = mem[rs1]; mem[rs1] = mem[rs1] op rs2.
+// Description: Atomically loads a word from the address in rs1,
+// writes the result of the operation back and places the
+// original value into rd. The address must be word-aligned.
+// There's only one hart, so the ordering bits (aq/rl) are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AmoInst {
+    AMOSWAP,
+    AMOADD,
+    AMOXOR,
+    AMOAND,
+    AMOOR,
+    // signed minimum/maximum (values compared as i32)
+    AMOMIN,
+    AMOMAX,
+    // unsigned minimum/maximum (values compared as u32)
+    AMOMINU,
+    AMOMAXU,
+}
+
+impl AmoInst {
+    // Computes the value stored back to memory
+    // from the loaded value and rs2.
+    fn op(self) -> impl FnOnce(u32, u32) -> u32 {
+        match self {
+            AmoInst::AMOSWAP => |_, rs2| rs2,
+            AmoInst::AMOADD => u32::wrapping_add,
+            AmoInst::AMOXOR => u32::bitxor,
+            AmoInst::AMOAND => u32::bitand,
+            AmoInst::AMOOR => u32::bitor,
+            AmoInst::AMOMIN => |mem, rs2| (mem as i32).min(rs2 as i32) as u32,
+            AmoInst::AMOMAX => |mem, rs2| (mem as i32).max(rs2 as i32) as u32,
+            AmoInst::AMOMINU => u32::min,
+            AmoInst::AMOMAXU => u32::max,
+        }
+    }
+}
+
+// instruction formats as named in the spec
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormatKind {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+// Decoded instruction together with its encoding fields, so tools built on
+// the decoder (disassembler, dependency analysis) don't have to re-derive them.
+// Fields the format doesn't have are `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInst {
+    pub inst: Inst,
+    pub format: FormatKind,
+    pub opcode: usize,
+    pub rd: Option<usize>,
+    pub rs1: Option<usize>,
+    pub rs2: Option<usize>,
+    pub imm: Option<u32>,
+}
+
+impl DecodedInst {
+    pub fn new(inst: Inst, opcode: usize) -> Self {
+        let (format, rd, rs1, rs2, imm) = match &inst {
+            Inst::R(_, f) | Inst::Amo(_, f) => {
+                (FormatKind::R, Some(f.rd), Some(f.rs1), Some(f.rs2), None)
+            }
+            Inst::I(_, f) => (FormatKind::I, Some(f.rd), Some(f.rs1), None, Some(f.imm)),
+            Inst::S(_, f) => (FormatKind::S, None, Some(f.rs1), Some(f.rs2), Some(f.imm)),
+            Inst::B(_, f) => (FormatKind::B, None, Some(f.rs1), Some(f.rs2), Some(f.imm)),
+            Inst::J(f) => (FormatKind::J, Some(f.rd), None, None, Some(f.imm)),
+            Inst::U(_, f) => (FormatKind::U, Some(f.rd), None, None, Some(f.imm)),
+            // I-type encodings, but their fields aren't kept
+            Inst::Csr(inst, f) => {
+                let rs1 = (!inst.is_imm()).then_some(f.rs1);
+                (FormatKind::I, Some(f.rd), rs1, None, Some(f.imm & 0xfff))
+            }
+            Inst::Fence(_) | Inst::SysCall(_) => (FormatKind::I, None, None, None, None),
+            // custom instructions may use any layout
+            Inst::SfenceVma(f) => (FormatKind::R, None, Some(f.rs1), Some(f.rs2), None),
+            Inst::Custom(_) => (FormatKind::R, None, None, None, None),
+        };
+        DecodedInst {
+            inst,
+            format,
+            opcode,
+            rd,
+            rs1,
+            rs2,
+            imm,
         }
     }
 }
 
 impl Inst {
-    pub fn execute(self, cpu: &mut Cpu) {
+    // conditional branches
+    pub fn is_branch(&self) -> bool {
+        matches!(self, Inst::B(..))
+    }
+
+    // unconditional jumps, both direct (jal) and indirect (jalr)
+    pub fn is_jump(&self) -> bool {
+        matches!(self, Inst::J(..) | Inst::I(IInst::Jalr, _))
+    }
+
+    pub fn is_load(&self) -> bool {
+        matches!(self, Inst::I(IInst::Mem(_), _))
+    }
+
+    pub fn is_store(&self) -> bool {
+        matches!(self, Inst::S(..))
+    }
+
+    // loads, stores and AMOs (which do both)
+    pub fn is_memory(&self) -> bool {
+        self.is_load() || self.is_store() || matches!(self, Inst::Amo(..))
+    }
+
+    // `ecall` and `ebreak`
+    pub fn is_syscall(&self) -> bool {
+        matches!(self, Inst::SysCall(SysCall::Ecall | SysCall::Break))
+    }
+
+    // `addi x0, x0, 0`, the canonical `nop` (and what `c.nop` expands to).
+    // Other instructions writing x0 have no effect either, but aren't used as padding.
+    pub fn is_nop(&self) -> bool {
+        matches!(
+            self,
+            Inst::I(IInst::Arith(ArithIInst::ADDI), IFormat { rd: 0, rs1: 0, imm: 0, .. })
+        )
+    }
+
+    // Instructions that may change the PC to something
+    // other than the next instruction.
+    pub fn is_control_flow(&self) -> bool {
+        self.is_branch() || self.is_jump()
+    }
+
+    // Mnemonic of the actual instruction, never a pseudo-instruction
+    // (`addi` rather than `nop`, `li` or `mv`).
+    pub fn mnemonic(&self) -> String {
+        match self {
+            Inst::R(inst, _) => mnemonic(inst),
+            Inst::I(IInst::Arith(inst), _) => mnemonic(inst),
+            Inst::I(IInst::Mem(inst), _) => mnemonic(inst),
+            Inst::I(IInst::Jalr, _) => "jalr".to_string(),
+            Inst::S(inst, _) => mnemonic(inst),
+            Inst::B(inst, _) => mnemonic(inst),
+            Inst::J(_) => "jal".to_string(),
+            Inst::U(inst, _) => mnemonic(inst),
+            Inst::Amo(inst, _) => format!("{}.w", mnemonic(inst)),
+            Inst::Fence(FenceInst::FENCE) => "fence".to_string(),
+            Inst::Fence(FenceInst::TSO) => "fence.tso".to_string(),
+            Inst::Fence(FenceInst::PAUSE) => "pause".to_string(),
+            Inst::SysCall(SysCall::Ecall) => "ecall".to_string(),
+            Inst::SysCall(SysCall::Break) => "ebreak".to_string(),
+            Inst::SysCall(call) => mnemonic(call),
+            Inst::Csr(inst, _) => mnemonic(inst),
+            Inst::SfenceVma(_) => "sfence.vma".to_string(),
+            Inst::Custom(_) => ".insn".to_string(),
+        }
+    }
+
+    // Registers the instruction reads, without `x0` as it's always 0.
+    // `ecall` reads the syscall number (a7) and the arguments (a0-a2).
+    pub fn reads(&self) -> Vec<usize> {
+        let regs = match self {
+            Inst::R(_, f) | Inst::Amo(_, f) => vec![f.rs1, f.rs2],
+            Inst::I(_, f) => vec![f.rs1],
+            Inst::S(_, f) => vec![f.rs1, f.rs2],
+            Inst::B(_, f) => vec![f.rs1, f.rs2],
+            Inst::SysCall(SysCall::Ecall) => vec![10, 11, 12, 17],
+            Inst::Csr(inst, f) if !inst.is_imm() => vec![f.rs1],
+            Inst::SfenceVma(f) => vec![f.rs1, f.rs2],
+            Inst::J(_) | Inst::U(..) | Inst::Fence(_) | Inst::SysCall(_) | Inst::Csr(..) => vec![],
+            // unknown without the custom decoder's help
+            Inst::Custom(_) => vec![],
+        };
+        let mut regs: Vec<usize> = regs.into_iter().filter(|&reg| reg != 0).collect();
+        regs.dedup();
+        regs
+    }
+
+    // Register the instruction writes, if any (writes to `x0` are discarded).
+    // `ecall` returns its result in a0.
+    pub fn writes(&self) -> Option<usize> {
+        let rd = match self {
+            Inst::R(_, f) | Inst::Amo(_, f) => f.rd,
+            Inst::I(_, f) => f.rd,
+            Inst::J(f) => f.rd,
+            Inst::U(_, f) => f.rd,
+            Inst::SysCall(SysCall::Ecall) => 10,
+            Inst::Csr(_, f) => f.rd,
+            Inst::S(..) | Inst::B(..) | Inst::Fence(_) | Inst::SysCall(_) => 0,
+            Inst::SfenceVma(_) => 0,
+            Inst::Custom(_) => 0,
+        };
+        (rd != 0).then_some(rd)
+    }
+
+    // Raw 32-bit word of the instruction, the inverse of `Cpu::decode`.
+    // Fences don't keep their fields, so they encode as the canonical
+    // `fence iorw, iorw`, `fence.tso` or `pause`.
+    // The function fields come from the instruction, not from the format.
+    pub fn encode(&self) -> u32 {
+        let r = |opcode, funct3, funct7, f: &RFormat| {
+            RFormat {
+                funct3,
+                funct7,
+                ..f.clone()
+            }
+            .encode(opcode)
+        };
+        let i = |opcode, funct3, f: &IFormat| IFormat { funct3, ..f.clone() }.encode(opcode);
+        match self {
+            Inst::R(inst, f) => {
+                let (funct3, funct7) = match inst {
+                    RInst::ADD => (0x0, 0x00),
+                    RInst::SUB => (0x0, 0x20),
+                    RInst::XOR => (0x4, 0x00),
+                    RInst::OR => (0x6, 0x00),
+                    RInst::AND => (0x7, 0x00),
+                    RInst::SLL => (0x1, 0x00),
+                    RInst::SRL => (0x5, 0x00),
+                    RInst::SRA => (0x5, 0x20),
+                    RInst::SLT => (0x2, 0x00),
+                    RInst::SLTU => (0x3, 0x00),
+                    RInst::MUL => (0x0, 0x01),
+                    RInst::MULH => (0x1, 0x01),
+                    RInst::MULHSU => (0x2, 0x01),
+                    RInst::MULHU => (0x3, 0x01),
+                    RInst::DIV => (0x4, 0x01),
+                    RInst::DIVU => (0x5, 0x01),
+                    RInst::REM => (0x6, 0x01),
+                    RInst::REMU => (0x7, 0x01),
+                    RInst::ANDN => (0x7, 0x20),
+                    RInst::ORN => (0x6, 0x20),
+                    RInst::XNOR => (0x4, 0x20),
+                    RInst::MIN => (0x4, 0x05),
+                    RInst::MINU => (0x5, 0x05),
+                    RInst::MAX => (0x6, 0x05),
+                    RInst::MAXU => (0x7, 0x05),
+                    RInst::ROL => (0x1, 0x30),
+                    RInst::ROR => (0x5, 0x30),
+                    // Only exist as immediates, where the rs2 field selects the operation.
+                    RInst::CLZ | RInst::CTZ | RInst::CPOP | RInst::SEXTB | RInst::SEXTH => {
+                        let inst = match inst {
+                            RInst::CLZ => ArithIInst::CLZ,
+                            RInst::CTZ => ArithIInst::CTZ,
+                            RInst::CPOP => ArithIInst::CPOP,
+                            RInst::SEXTB => ArithIInst::SEXTB,
+                            _ => ArithIInst::SEXTH,
+                        };
+                        let f = IFormat {
+                            rd: f.rd,
+                            funct3: 0x1,
+                            rs1: f.rs1,
+                            imm: 0,
+                        };
+                        return Inst::I(IInst::Arith(inst), f).encode();
+                    }
+                };
+                r(0b0110011, funct3, funct7, f)
+            }
+            Inst::I(IInst::Arith(inst), f) => {
+                // the upper bits of shift immediates select the operation
+                let (funct3, upper_imm) = match inst {
+                    ArithIInst::ADDI => (0x0, 0x000),
+                    ArithIInst::SLTI => (0x2, 0x000),
+                    ArithIInst::SLTIU => (0x3, 0x000),
+                    ArithIInst::XORI => (0x4, 0x000),
+                    ArithIInst::ORI => (0x6, 0x000),
+                    ArithIInst::ANDI => (0x7, 0x000),
+                    ArithIInst::SLLI => (0x1, 0x000),
+                    ArithIInst::SRLI => (0x5, 0x000),
+                    ArithIInst::SRAI => (0x5, 0x400),
+                    ArithIInst::RORI => (0x5, 0x600),
+                    ArithIInst::CLZ => (0x1, 0x600),
+                    ArithIInst::CTZ => (0x1, 0x601),
+                    ArithIInst::CPOP => (0x1, 0x602),
+                    ArithIInst::SEXTB => (0x1, 0x604),
+                    ArithIInst::SEXTH => (0x1, 0x605),
+                };
+                i(0b0010011, funct3, f) | (upper_imm << 20)
+            }
+            Inst::I(IInst::Mem(inst), f) => {
+                let funct3 = match inst {
+                    LoadIInst::LB => 0x0,
+                    LoadIInst::LH => 0x1,
+                    LoadIInst::LW => 0x2,
+                    LoadIInst::LBU => 0x4,
+                    LoadIInst::LHU => 0x5,
+                };
+                i(0b0000011, funct3, f)
+            }
+            Inst::I(IInst::Jalr, f) => i(0b1100111, 0x0, f),
+            Inst::S(inst, f) => {
+                let funct3 = match inst {
+                    SInst::SB => 0x0,
+                    SInst::SH => 0x1,
+                    SInst::SW => 0x2,
+                };
+                SFormat { funct3, ..f.clone() }.encode(0b0100011)
+            }
+            Inst::B(inst, f) => {
+                let funct3 = match inst {
+                    BInst::BEQ => 0x0,
+                    BInst::BNE => 0x1,
+                    BInst::BLT => 0x4,
+                    BInst::BGE => 0x5,
+                    BInst::BLTU => 0x6,
+                    BInst::BGEU => 0x7,
+                };
+                BFormat { funct3, ..f.clone() }.encode(0b1100011)
+            }
+            Inst::J(f) => f.encode(0b1101111),
+            Inst::U(inst, f) => {
+                let opcode = match inst {
+                    UInst::LUI => 0b0110111,
+                    UInst::AUIPC => 0b0010111,
+                };
+                f.encode(opcode)
+            }
+            Inst::Amo(inst, f) => {
+                let funct5 = match inst {
+                    AmoInst::AMOADD => 0x00,
+                    AmoInst::AMOSWAP => 0x01,
+                    AmoInst::AMOXOR => 0x04,
+                    AmoInst::AMOOR => 0x08,
+                    AmoInst::AMOAND => 0x0c,
+                    AmoInst::AMOMIN => 0x10,
+                    AmoInst::AMOMAX => 0x14,
+                    AmoInst::AMOMINU => 0x18,
+                    AmoInst::AMOMAXU => 0x1c,
+                };
+                // keeps the aq/rl bits
+                let funct7 = (funct5 << 2) | get_bits!(f.funct7, 0, 1);
+                r(0b0101111, 0x2, funct7, f)
+            }
+            Inst::Fence(FenceInst::FENCE) => 0x0ff0000f,
+            Inst::Fence(FenceInst::TSO) => 0x8330000f,
+            Inst::Fence(FenceInst::PAUSE) => 0x0100000f,
+            Inst::SysCall(call) => {
+                let funct12 = match call {
+                    SysCall::Ecall => 0x000,
+                    SysCall::Break => 0x001,
+                    SysCall::Sret => 0x102,
+                    SysCall::Mret => 0x302,
+                    SysCall::Wfi => 0x105,
+                };
+                (funct12 << 20) | 0b1110011
+            }
+            Inst::Csr(inst, f) => {
+                let funct3 = match inst {
+                    CsrInst::CSRRW => 0x1,
+                    CsrInst::CSRRS => 0x2,
+                    CsrInst::CSRRC => 0x3,
+                    CsrInst::CSRRWI => 0x5,
+                    CsrInst::CSRRSI => 0x6,
+                    CsrInst::CSRRCI => 0x7,
+                };
+                i(0b1110011, funct3, f)
+            }
+            Inst::SfenceVma(f) => r(0b1110011, 0x0, 0x09, f),
+            Inst::Custom(raw_inst) => *raw_inst,
+        }
+    }
+
+    pub fn execute(self, cpu: &mut Cpu) -> Result<(), Error> {
         match self {
             Inst::R(inst, format) => {
                 let rs1 = cpu.regs.read(format.rs1);
                 let rs2 = cpu.regs.read(format.rs2);
+                if rs2 == 0 && inst.is_div() && cpu.traps_div_by_zero() {
+                    // PC already points to the next instruction
+                    return Err(Error::DivideByZero(cpu.pc.current()));
+                }
+                if cpu.warns_on_overflow() && inst.signed_overflow(rs1, rs2) {
+                    cpu.warn(Warning::SignedOverflow(cpu.pc.current()));
+                }
                 // Arithmetic Logic Unit (ALU)
                 let alu = inst.op();
                 let result = alu(rs1, rs2);
@@ -308,15 +945,21 @@ impl Inst {
             }
             Inst::I(inst, format) => {
                 let rs1 = cpu.regs.read(format.rs1);
+                if let IInst::Arith(ArithIInst::ADDI) = inst
+                    && cpu.warns_on_overflow()
+                    && RInst::ADD.signed_overflow(rs1, format.imm)
+                {
+                    cpu.warn(Warning::SignedOverflow(cpu.pc.current()));
+                }
                 let alu = inst.op(cpu);
-                let result = alu(rs1, format.imm);
+                let result = alu(rs1, format.imm)?;
                 cpu.regs.write(format.rd, result);
             }
             Inst::S(inst, format) => {
                 let rs1 = cpu.regs.read(format.rs1);
                 let rs2 = cpu.regs.read(format.rs2);
-                let alu = inst.op(&mut cpu.mem);
-                alu(rs1, rs2, format.imm);
+                let alu = inst.op(cpu);
+                alu(rs1, rs2, format.imm)?;
             }
             Inst::B(inst, format) => {
                 let rs1 = cpu.regs.read(format.rs1);
@@ -329,40 +972,280 @@ impl Inst {
                     BInst::BGE => rs1 as i32 >= rs2 as i32,
                     BInst::BGEU => rs1 >= rs2,
                 };
-                // TODO: what does it do?
+                // The immediate value in a jump instruction
+                // is encoded as an offset relative to the
+                // current instruction's address (not the next one).
                 if branch {
-                    // The immediate value in a jump instruction
-                    // is typically encoded as an offset relative
-                    // to the current instruction's address (not the next one).
-                    // Since the CPU has already incremented the PC by 4,
-                    // you need to compensate by subtracting 4 to make the offset correct:
-                    // jump = (current_pc + 4) + (offset - 4) = current_pc + offset
-                    cpu.pc.set(u32::wrapping_add(
-                        cpu.pc.get(),
-                        u32::wrapping_sub(format.imm, 4),
-                    ));
+                    cpu.pc.rel_jump(format.imm as i32);
                 }
             }
             Inst::J(format) => {
                 cpu.regs.write(format.rd, cpu.pc.get());
-                cpu.pc.set(u32::wrapping_add(
-                    cpu.pc.get(),
-                    u32::wrapping_sub(format.imm, 4),
-                ));
+                cpu.pc.rel_jump(format.imm as i32);
             }
             Inst::U(inst, format) => {
-                let alu = inst.op(cpu.pc.get());
+                let alu = inst.op(cpu.pc.current());
                 let result = alu(format.imm);
                 cpu.regs.write(format.rd, result);
             }
+            Inst::Amo(inst, format) => {
+                let addr = cpu.regs.read(format.rs1);
+                let rs2 = cpu.regs.read(format.rs2);
+                // misaligned AMOs always trap
+                if !Size::Word.is_aligned(addr) {
+                    return Err(Error::StoreAddressMisaligned(addr));
+                }
+                let loaded = cpu.load(addr, Size::Word, true)?;
+                let alu = inst.op();
+                cpu.store(addr, Size::Word, alu(loaded, rs2))?;
+                cpu.regs.write(format.rd, loaded);
+            }
+            Inst::SfenceVma(format) => {
+                let vaddr = (format.rs1 != 0).then(|| cpu.regs.read(format.rs1));
+                let asid = (format.rs2 != 0).then(|| cpu.regs.read(format.rs2));
+                cpu.sfence_vma(vaddr, asid);
+            }
+            Inst::Custom(raw_inst) => cpu.execute_custom(raw_inst)?,
+            Inst::Fence(_) => cpu.fence(),
+            Inst::Csr(inst, format) => {
+                let csr = format.imm & 0xfff;
+                let illegal =
+                    || Error::IllegalInstruction(Inst::Csr(inst.clone(), format.clone()).encode());
+                let old = cpu.csr_read(csr).ok_or_else(illegal)?;
+                let src = match inst.is_imm() {
+                    true => format.rs1 as u32,
+                    false => cpu.regs.read(format.rs1),
+                };
+                let new = match inst {
+                    CsrInst::CSRRW | CsrInst::CSRRWI => src,
+                    CsrInst::CSRRS | CsrInst::CSRRSI => old | src,
+                    CsrInst::CSRRC | CsrInst::CSRRCI => old & !src,
+                };
+                // setting or clearing bits from x0 (or a zero immediate) doesn't write
+                if matches!(inst, CsrInst::CSRRW | CsrInst::CSRRWI) || format.rs1 != 0 {
+                    // csr[11:10] == 0b11 are the read-only CSRs
+                    if csr >> 10 == 0b11 {
+                        return Err(illegal());
+                    }
+                    cpu.csr_write(csr, new);
+                }
+                cpu.regs.write(format.rd, old);
+            }
+            Inst::SysCall(SysCall::Mret) => return Err(Error::Unsupported("mret without traps")),
+            Inst::SysCall(SysCall::Sret) => return Err(Error::Unsupported("sret without traps")),
             Inst::SysCall(..) => {}
         }
+        Ok(())
+    }
+}
+
+// Mnemonic as written in assembly, e.g. `SEXTB` is `sext.b`.
+fn mnemonic(inst: &impl Debug) -> String {
+    let name = format!("{inst:?}").to_lowercase();
+    match name.strip_prefix("sext") {
+        Some(size) => format!("sext.{size}"),
+        None => name,
+    }
+}
+
+// Zbb instructions with a single source register
+fn is_unary(inst: &RInst) -> bool {
+    matches!(inst, RInst::CLZ | RInst::CTZ | RInst::CPOP | RInst::SEXTB | RInst::SEXTH)
+}
+
+// Disassembly in the same syntax `objdump` uses, including its
+// pseudo-instructions (`nop`, `mv`, `ret`, `j`, ...) for the canonical
+// encodings. Branch and jump targets are printed as offsets as the
+// instruction doesn't know its own address.
+impl Display for Inst {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inst::R(inst, r) if is_unary(inst) => {
+                write!(f, "{} x{}, x{}", mnemonic(inst), r.rd, r.rs1)
+            }
+            Inst::R(inst, r) => write!(f, "{} x{}, x{}, x{}", mnemonic(inst), r.rd, r.rs1, r.rs2),
+            Inst::I(IInst::Arith(inst), i) => {
+                let imm = i.imm as i32;
+                match inst {
+                    ArithIInst::ADDI if i.rd == 0 && i.rs1 == 0 && imm == 0 => write!(f, "nop"),
+                    ArithIInst::ADDI if i.rs1 == 0 => write!(f, "li x{}, {imm}", i.rd),
+                    ArithIInst::ADDI if imm == 0 => write!(f, "mv x{}, x{}", i.rd, i.rs1),
+                    ArithIInst::XORI if imm == -1 => write!(f, "not x{}, x{}", i.rd, i.rs1),
+                    ArithIInst::SLTIU if imm == 1 => write!(f, "seqz x{}, x{}", i.rd, i.rs1),
+                    ArithIInst::SLLI | ArithIInst::SRLI | ArithIInst::SRAI | ArithIInst::RORI => {
+                        // the upper bits select the operation
+                        let shamt = i.imm & 0x1f;
+                        write!(f, "{} x{}, x{}, {shamt}", mnemonic(inst), i.rd, i.rs1)
+                    }
+                    ArithIInst::CLZ
+                    | ArithIInst::CTZ
+                    | ArithIInst::CPOP
+                    | ArithIInst::SEXTB
+                    | ArithIInst::SEXTH => write!(f, "{} x{}, x{}", mnemonic(inst), i.rd, i.rs1),
+                    _ => write!(f, "{} x{}, x{}, {imm}", mnemonic(inst), i.rd, i.rs1),
+                }
+            }
+            Inst::I(IInst::Mem(inst), i) => {
+                write!(f, "{} x{}, {}(x{})", mnemonic(inst), i.rd, i.imm as i32, i.rs1)
+            }
+            Inst::I(IInst::Jalr, i) => match (i.rd, i.imm) {
+                (0, 0) if i.rs1 == 1 => write!(f, "ret"),
+                (0, 0) => write!(f, "jr x{}", i.rs1),
+                (1, 0) => write!(f, "jalr x{}", i.rs1),
+                _ => write!(f, "jalr x{}, {}(x{})", i.rd, i.imm as i32, i.rs1),
+            },
+            Inst::S(inst, s) => {
+                write!(f, "{} x{}, {}(x{})", mnemonic(inst), s.rs2, s.imm as i32, s.rs1)
+            }
+            Inst::B(inst, b) => {
+                let offset = b.imm as i32;
+                match inst {
+                    BInst::BEQ if b.rs1 == 0 && b.rs2 == 0 => write!(f, "j {offset}"),
+                    BInst::BEQ if b.rs2 == 0 => write!(f, "beqz x{}, {offset}", b.rs1),
+                    BInst::BNE if b.rs2 == 0 => write!(f, "bnez x{}, {offset}", b.rs1),
+                    _ => write!(f, "{} x{}, x{}, {offset}", mnemonic(inst), b.rs1, b.rs2),
+                }
+            }
+            Inst::J(j) => match j.rd {
+                0 => write!(f, "j {}", j.imm as i32),
+                1 => write!(f, "jal {}", j.imm as i32),
+                rd => write!(f, "jal x{rd}, {}", j.imm as i32),
+            },
+            Inst::U(inst, u) => write!(f, "{} x{}, {:#x}", mnemonic(inst), u.rd, u.imm & 0xfffff),
+            Inst::Amo(inst, r) => {
+                write!(f, "{}.w x{}, x{}, (x{})", mnemonic(inst), r.rd, r.rs2, r.rs1)
+            }
+            Inst::Fence(FenceInst::FENCE) => write!(f, "fence"),
+            Inst::Fence(FenceInst::TSO) => write!(f, "fence.tso"),
+            Inst::Fence(FenceInst::PAUSE) => write!(f, "pause"),
+            Inst::SysCall(SysCall::Ecall) => write!(f, "ecall"),
+            Inst::SysCall(SysCall::Break) => write!(f, "ebreak"),
+            Inst::SysCall(SysCall::Mret) => write!(f, "mret"),
+            Inst::SysCall(SysCall::Sret) => write!(f, "sret"),
+            Inst::SysCall(SysCall::Wfi) => write!(f, "wfi"),
+            Inst::Csr(inst, i) => {
+                let csr = i.imm & 0xfff;
+                match inst {
+                    CsrInst::CSRRS if i.rs1 == 0 => write!(f, "csrr x{}, {csr:#x}", i.rd),
+                    CsrInst::CSRRW if i.rd == 0 => write!(f, "csrw {csr:#x}, x{}", i.rs1),
+                    inst if inst.is_imm() => {
+                        write!(f, "{} x{}, {csr:#x}, {}", mnemonic(inst), i.rd, i.rs1)
+                    }
+                    _ => write!(f, "{} x{}, {csr:#x}, x{}", mnemonic(inst), i.rd, i.rs1),
+                }
+            }
+            Inst::SfenceVma(r) => match (r.rs1, r.rs2) {
+                (0, 0) => write!(f, "sfence.vma"),
+                (rs1, 0) => write!(f, "sfence.vma x{rs1}"),
+                (rs1, rs2) => write!(f, "sfence.vma x{rs1}, x{rs2}"),
+            },
+            Inst::Custom(raw_inst) => write!(f, ".insn 4, {raw_inst:#010x}"),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory::MEM_SIZE;
+
+    #[test]
+    fn display() {
+        let cpu = Cpu::new(false);
+        let disasm = |raw| cpu.decode(raw).unwrap().to_string();
+        // addi x0, x0, 0
+        assert_eq!(disasm(0x00000013), "nop");
+        // addi x5, x6, 0
+        assert_eq!(disasm(0x00030293), "mv x5, x6");
+        // addi x1, x0, -1
+        assert_eq!(disasm(0xfff00093), "li x1, -1");
+        // jalr x0, 0(x1)
+        assert_eq!(disasm(0x00008067), "ret");
+        // beq x0, x0, -8
+        assert_eq!(disasm(0xfe000ce3), "j -8");
+        // jal x0, 16
+        assert_eq!(disasm(0x0100006f), "j 16");
+        // lui x1, 0xfffff
+        assert_eq!(disasm(0xfffff0b7), "lui x1, 0xfffff");
+        // srai x1, x2, 4
+        assert_eq!(disasm(0x40415093), "srai x1, x2, 4");
+        // amoadd.w x3, x2, (x1)
+        assert_eq!(disasm(0x0020a1af), "amoadd.w x3, x2, (x1)");
+        // not a pseudo-instruction
+        assert_eq!(disasm(0x003100b3), "add x1, x2, x3");
+        assert_eq!(disasm(0x00c12303), "lw x6, 12(x2)");
+        assert_eq!(disasm(0x00112023), "sw x1, 0(x2)");
+        assert_eq!(disasm(0x00208463), "beq x1, x2, 8");
+        assert_eq!(disasm(0x30200073), "mret");
+        assert_eq!(disasm(0x300020f3), "csrr x1, 0x300");
+        assert_eq!(disasm(0x30529073), "csrw 0x305, x5");
+        assert_eq!(disasm(0x340291f3), "csrrw x3, 0x340, x5");
+        assert_eq!(disasm(0x12000073), "sfence.vma");
+        assert_eq!(disasm(0x12b50073), "sfence.vma x10, x11");
+    }
+
+    #[test]
+    fn register_dependencies() {
+        let cpu = Cpu::new(false);
+        // add x1, x2, x3
+        let add = cpu.decode(0x003100b3).unwrap();
+        assert_eq!((add.reads(), add.writes()), (vec![2, 3], Some(1)));
+        // sw x1, 0(x2)
+        let sw = cpu.decode(0x00112023).unwrap();
+        assert_eq!((sw.reads(), sw.writes()), (vec![2, 1], None));
+        // addi x0, x0, 0
+        let nop = cpu.decode(0x00000013).unwrap();
+        assert_eq!((nop.reads(), nop.writes()), (vec![], None));
+        // add x1, x2, x2
+        assert_eq!(cpu.decode(0x002100b3).unwrap().reads(), [2]);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let cpu = Cpu::new(false).with_zbb();
+        let mut rng = crate::rng::XorShift::default();
+        let opcodes = [
+            0b0110011, 0b0010011, 0b0000011, 0b1100111, 0b0100011, 0b1100011, 0b0101111,
+            0b1101111, 0b0110111, 0b0010111, 0b1110011,
+        ];
+        let mut decoded = 0;
+        for n in 0..20_000 {
+            let bits = rng.next_u64() as u32;
+            let raw = (bits & !0x7f) | opcodes[n % opcodes.len()];
+            let Ok(inst) = cpu.decode(raw) else { continue };
+            decoded += 1;
+            assert_eq!(inst.encode(), raw, "{inst}");
+            assert_eq!(cpu.decode(inst.encode()).unwrap(), inst);
+        }
+        assert!(decoded > 5_000);
+        // scattered immediates: bge x0, x0, -12 and jal x0, -2048
+        for raw in [0xfe005ae3, 0x801ff06f, 0x00000073, 0x0100000f, 0x8330000f] {
+            assert_eq!(cpu.decode(raw).unwrap().encode(), raw);
+        }
+    }
+
+    #[test]
+    fn classify() {
+        let cpu = Cpu::new(false);
+        // beq x1, x2, 8
+        let beq = cpu.decode(0x00208463).unwrap();
+        assert!(beq.is_branch() && beq.is_control_flow());
+        assert!(!beq.is_jump() && !beq.is_memory());
+        // jalr x0, 0(x1)
+        let ret = cpu.decode(0x00008067).unwrap();
+        assert!(ret.is_jump() && ret.is_control_flow());
+        // lw x6, 12(x2)
+        let lw = cpu.decode(0x00c12303).unwrap();
+        assert!(lw.is_load() && lw.is_memory() && !lw.is_store());
+        // add x1, x2, x3
+        let add = cpu.decode(0x003100b3).unwrap();
+        assert!(!add.is_memory() && !add.is_control_flow() && !add.is_syscall());
+        // ecall
+        assert!(cpu.decode(0x00000073).unwrap().is_syscall());
+        // addi x0, x0, 0 and addi x0, x0, 1
+        assert!(cpu.decode(0x00000013).unwrap().is_nop());
+        assert!(!cpu.decode(0x00100013).unwrap().is_nop());
+    }
 
     #[test]
     fn store_byte() {
@@ -381,8 +1264,305 @@ mod tests {
                 imm: 3,
             }
         );
-        inst.execute(&mut cpu);
-        assert_eq!(cpu.mem.read(3, Size::Byte, true), 12)
+        inst.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.mem.read(3, Size::Byte, true).unwrap(), 12)
+    }
+
+    fn store(cpu: &mut Cpu, inst: SInst, val: u32) {
+        cpu.regs.write(28, val);
+        // op t3, 0x100(x0)
+        Inst::S(
+            inst,
+            SFormat {
+                funct3: 0x0,
+                rs1: 0,
+                rs2: 28,
+                imm: 0x100,
+            },
+        )
+        .execute(cpu)
+        .unwrap();
+    }
+
+    #[test]
+    fn store_byte_truncates() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x100, Size::Word, 0xaaaaaaaa).unwrap();
+        store(&mut cpu, SInst::SB, 0xffffff34);
+        // only the lowest byte is written
+        assert_eq!(cpu.mem.read(0x100, Size::Word, true).unwrap(), 0xaaaaaa34);
+    }
+
+    #[test]
+    fn store_half_word_truncates() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x100, Size::Word, 0xaaaaaaaa).unwrap();
+        store(&mut cpu, SInst::SH, 0x12345678);
+        assert_eq!(cpu.mem.read(0x100, Size::Byte, true).unwrap(), 0x78);
+        assert_eq!(cpu.mem.read(0x101, Size::Byte, true).unwrap(), 0x56);
+        assert_eq!(cpu.mem.read(0x102, Size::Byte, true).unwrap(), 0xaa);
+        store(&mut cpu, SInst::SW, 0x12345678);
+        assert_eq!(cpu.mem.read(0x100, Size::Word, true).unwrap(), 0x12345678);
+    }
+
+    #[test]
+    fn misaligned_load_emulated() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(4, Size::Word, 0x12345678).unwrap();
+        cpu.mem.write(8, Size::Word, 0x9abcdef0).unwrap();
+        // lw x1, 5(x0)
+        let inst = Inst::I(
+            IInst::Mem(LoadIInst::LW),
+            IFormat {
+                rd: 1,
+                funct3: 0x2,
+                rs1: 0,
+                imm: 5,
+            },
+        );
+        inst.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.regs.read(1), 0xf0123456);
+    }
+
+    #[test]
+    fn misaligned_load_trap() {
+        let mut cpu = Cpu::new(false).with_misaligned_access(MisalignedAccess::Trap);
+        // lw x1, 5(x0)
+        let inst = Inst::I(
+            IInst::Mem(LoadIInst::LW),
+            IFormat {
+                rd: 1,
+                funct3: 0x2,
+                rs1: 0,
+                imm: 5,
+            },
+        );
+        assert!(matches!(
+            inst.execute(&mut cpu),
+            Err(Error::LoadAddressMisaligned(5))
+        ));
+        assert_eq!(cpu.regs.read(1), 0);
+    }
+
+    #[test]
+    fn load_wrapped_address() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x10, Size::Word, 42).unwrap();
+        cpu.regs.write(5, 0xfffffff0);
+        // lw x1, 0x20(x5)
+        let inst = Inst::I(
+            IInst::Mem(LoadIInst::LW),
+            IFormat {
+                rd: 1,
+                funct3: 0x2,
+                rs1: 5,
+                imm: 0x20,
+            },
+        );
+        inst.execute(&mut cpu).unwrap();
+        assert_eq!(cpu.regs.read(1), 42);
+    }
+
+    #[test]
+    fn load_out_of_bounds() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(5, 0x2_0000);
+        // lw x1, 0(x5)
+        let inst = Inst::I(
+            IInst::Mem(LoadIInst::LW),
+            IFormat {
+                rd: 1,
+                funct3: 0x2,
+                rs1: 5,
+                imm: 0,
+            },
+        );
+        assert!(matches!(
+            inst.execute(&mut cpu),
+            Err(Error::LoadAccessFault(0x2_0000, MEM_SIZE))
+        ));
+    }
+
+    #[test]
+    fn store_out_of_bounds() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(5, 0x2_0000);
+        // sw x0, -2(x5)
+        let inst = Inst::S(
+            SInst::SW,
+            SFormat {
+                funct3: 0x2,
+                rs1: 5,
+                rs2: 0,
+                imm: -2i32 as u32,
+            },
+        );
+        assert!(matches!(
+            inst.execute(&mut cpu),
+            Err(Error::StoreAccessFault(0x1_fffe, MEM_SIZE))
+        ));
+    }
+
+    fn r(inst: RInst) -> Inst {
+        // op x3, x1, x2
+        Inst::R(
+            inst,
+            RFormat {
+                rd: 3,
+                funct3: 0,
+                rs1: 1,
+                rs2: 2,
+                funct7: 0x01,
+            },
+        )
+    }
+
+    fn exec_r(cpu: &mut Cpu, inst: RInst, rs1: u32, rs2: u32) -> Result<u32, Error> {
+        cpu.regs.write(1, rs1);
+        cpu.regs.write(2, rs2);
+        r(inst).execute(cpu)?;
+        Ok(cpu.regs.read(3))
+    }
+
+    #[test]
+    fn shift_right_arithmetic() {
+        let mut cpu = Cpu::new(false);
+        assert_eq!(exec_r(&mut cpu, RInst::SRA, 0x80000000, 4).unwrap(), 0xf8000000);
+        assert_eq!(exec_r(&mut cpu, RInst::SRA, 0x80000000, 31).unwrap(), 0xffffffff);
+        assert_eq!(exec_r(&mut cpu, RInst::SRA, 0x40000000, 4).unwrap(), 0x04000000);
+        // only the low 5 bits of rs2 are used
+        assert_eq!(exec_r(&mut cpu, RInst::SRA, 0x80000000, 36).unwrap(), 0xf8000000);
+
+        cpu.regs.write(2, 0x80000000);
+        // srai x1, x2, 4
+        cpu.execute_raw(0x40415093).unwrap();
+        assert_eq!(cpu.regs.read(1), 0xf8000000);
+        // srai x1, x2, 31
+        cpu.execute_raw(0x41f15093).unwrap();
+        assert_eq!(cpu.regs.read(1), 0xffffffff);
+    }
+
+    #[test]
+    fn multiply() {
+        let mut cpu = Cpu::new(false);
+        let minus_two = -2i32 as u32;
+        assert_eq!(exec_r(&mut cpu, RInst::MUL, minus_two, 3).unwrap(), -6i32 as u32);
+        assert_eq!(exec_r(&mut cpu, RInst::MULH, minus_two, 3).unwrap(), u32::MAX);
+        assert_eq!(exec_r(&mut cpu, RInst::MULHU, minus_two, 3).unwrap(), 2);
+        assert_eq!(exec_r(&mut cpu, RInst::MULHSU, minus_two, 3).unwrap(), u32::MAX);
+        assert_eq!(exec_r(&mut cpu, RInst::MULHSU, 3, minus_two).unwrap(), 2);
+    }
+
+    #[test]
+    fn bit_manipulation() {
+        let mut cpu = Cpu::new(false);
+        assert_eq!(exec_r(&mut cpu, RInst::ANDN, 0b1100, 0b1010).unwrap(), 0b0100);
+        assert_eq!(exec_r(&mut cpu, RInst::ORN, 0, 0xffff0000).unwrap(), 0x0000ffff);
+        assert_eq!(exec_r(&mut cpu, RInst::XNOR, 0xff00ff00, 0xffff0000).unwrap(), 0xff0000ff);
+        assert_eq!(exec_r(&mut cpu, RInst::ROL, 0x80000001, 33).unwrap(), 0x00000003);
+        assert_eq!(exec_r(&mut cpu, RInst::ROR, 0x80000001, 1).unwrap(), 0xc0000000);
+        assert_eq!(exec_r(&mut cpu, RInst::CTZ, 0x100, 0).unwrap(), 8);
+        assert_eq!(exec_r(&mut cpu, RInst::CLZ, 0, 0).unwrap(), 32);
+        assert_eq!(exec_r(&mut cpu, RInst::SEXTB, 0x1280, 0).unwrap(), 0xffffff80);
+        assert_eq!(exec_r(&mut cpu, RInst::SEXTH, 0x17fff, 0).unwrap(), 0x7fff);
+    }
+
+    #[test]
+    fn min_max() {
+        let mut cpu = Cpu::new(false);
+        let minus_one = -1i32 as u32;
+        assert_eq!(exec_r(&mut cpu, RInst::MIN, minus_one, 1).unwrap(), minus_one);
+        assert_eq!(exec_r(&mut cpu, RInst::MAX, minus_one, 1).unwrap(), 1);
+        assert_eq!(exec_r(&mut cpu, RInst::MINU, minus_one, 1).unwrap(), 1);
+        assert_eq!(exec_r(&mut cpu, RInst::MAXU, minus_one, 1).unwrap(), minus_one);
+    }
+
+    #[test]
+    fn divide() {
+        let mut cpu = Cpu::new(false);
+        let min = i32::MIN as u32;
+        assert_eq!(exec_r(&mut cpu, RInst::DIV, -7i32 as u32, 2).unwrap(), -3i32 as u32);
+        assert_eq!(exec_r(&mut cpu, RInst::REM, -7i32 as u32, 2).unwrap(), -1i32 as u32);
+        assert_eq!(exec_r(&mut cpu, RInst::DIV, min, u32::MAX).unwrap(), min);
+        assert_eq!(exec_r(&mut cpu, RInst::REM, min, u32::MAX).unwrap(), 0);
+        assert_eq!(exec_r(&mut cpu, RInst::DIVU, 7, 2).unwrap(), 3);
+        assert_eq!(exec_r(&mut cpu, RInst::REMU, 7, 2).unwrap(), 1);
+    }
+
+    #[test]
+    fn divide_by_zero_spec_values() {
+        let mut cpu = Cpu::new(false);
+        assert_eq!(exec_r(&mut cpu, RInst::DIV, 7, 0).unwrap(), u32::MAX);
+        assert_eq!(exec_r(&mut cpu, RInst::DIVU, 7, 0).unwrap(), u32::MAX);
+        assert_eq!(exec_r(&mut cpu, RInst::REM, 7, 0).unwrap(), 7);
+        assert_eq!(exec_r(&mut cpu, RInst::REMU, 7, 0).unwrap(), 7);
+    }
+
+    #[test]
+    fn divide_by_zero_trap() {
+        let mut cpu = Cpu::new(false).with_div_by_zero_trap();
+        // as if fetched from 0x1c
+        cpu.pc.set(0x1c);
+        cpu.pc.advance(4);
+        assert!(matches!(
+            exec_r(&mut cpu, RInst::DIVU, 7, 0),
+            Err(Error::DivideByZero(0x1c))
+        ));
+        assert_eq!(exec_r(&mut cpu, RInst::DIVU, 7, 1).unwrap(), 7);
+    }
+
+    fn amo(inst: AmoInst) -> Inst {
+        // amo*.w x3, x2, (x1)
+        Inst::Amo(
+            inst,
+            RFormat {
+                rd: 3,
+                funct3: 0x2,
+                rs1: 1,
+                rs2: 2,
+                funct7: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn amo_min_max_op() {
+        assert_eq!(AmoInst::AMOMIN.op()(u32::MAX, 1), u32::MAX);
+        assert_eq!(AmoInst::AMOMAX.op()(u32::MAX, 1), 1);
+        assert_eq!(AmoInst::AMOMINU.op()(u32::MAX, 1), 1);
+        assert_eq!(AmoInst::AMOMAXU.op()(u32::MAX, 1), u32::MAX);
+    }
+
+    #[test]
+    fn amomin_signed() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x100, Size::Word, 0xffffffff).unwrap();
+        cpu.regs.write(1, 0x100);
+        cpu.regs.write(2, 1);
+        amo(AmoInst::AMOMIN).execute(&mut cpu).unwrap();
+        assert_eq!(cpu.mem.read(0x100, Size::Word, true).unwrap() as i32, -1);
+        assert_eq!(cpu.regs.read(3) as i32, -1);
+    }
+
+    #[test]
+    fn amominu_unsigned() {
+        let mut cpu = Cpu::new(false);
+        cpu.mem.write(0x100, Size::Word, 0xffffffff).unwrap();
+        cpu.regs.write(1, 0x100);
+        cpu.regs.write(2, 1);
+        amo(AmoInst::AMOMINU).execute(&mut cpu).unwrap();
+        assert_eq!(cpu.mem.read(0x100, Size::Word, true).unwrap(), 1);
+        assert_eq!(cpu.regs.read(3), 0xffffffff);
+    }
+
+    #[test]
+    fn amo_misaligned() {
+        let mut cpu = Cpu::new(false);
+        cpu.regs.write(1, 0x102);
+        assert!(matches!(
+            amo(AmoInst::AMOSWAP).execute(&mut cpu),
+            Err(Error::StoreAddressMisaligned(0x102))
+        ));
     }
 
     #[test]
@@ -390,15 +1570,15 @@ mod tests {
         let mut cpu = Cpu::new(false);
 
         let inst = Inst::U(UInst::LUI, UFormat { rd: 10, imm: 1 });
-        inst.execute(&mut cpu);
+        inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(10), 4096);
 
         let inst = Inst::U(UInst::LUI, UFormat { rd: 10, imm: 3 });
-        inst.execute(&mut cpu);
+        inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(10), 12288);
 
         let inst = Inst::U(UInst::LUI, UFormat { rd: 10, imm: 0x100 });
-        inst.execute(&mut cpu);
+        inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(10), 1048576);
     }
 
@@ -409,18 +1589,37 @@ mod tests {
             rd: 10,
             imm: 0b1111_1111_1111_1111,
         });
-        inst.execute(&mut cpu);
+        inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(10), 0b1111_1111_1111_1111_0000_0000_0000);
     }
 
+    #[test]
+    fn jal_offsets_and_link() {
+        let mut cpu = Cpu::new(false);
+        let before = cpu.regs.dump_all();
+        cpu.pc.set(0x100);
+        // jal x0, 16 (j 16), relative to the jal itself
+        cpu.execute_raw(0x0100006f).unwrap();
+        assert_eq!(cpu.pc.get(), 0x110);
+        // no link written anywhere
+        assert_eq!(cpu.regs.dump_all(), before);
+
+        // jal x1, -8
+        cpu.execute_raw(0xff9ff0ef).unwrap();
+        assert_eq!(cpu.pc.get(), 0x108);
+        // address of the instruction after the jal
+        assert_eq!(cpu.regs.read(1), 0x114);
+    }
+
     #[test]
     fn long_jump() {
         // manually test big addresses, since emulator has little memory
         // auipc x5, 0x03000
         // jalr x10, x5, -0x400
         let mut cpu = Cpu::new(false);
-        // set pc to 0x40000004
-        cpu.pc.set(0x40000004);
+        // execute at 0x40000000, the next pc is 0x40000004
+        cpu.pc.set(0x40000000);
+        cpu.pc.advance(4);
         let auipc_inst = Inst::U(UInst::AUIPC, UFormat {
             rd: 5,
             imm: 0x3000,
@@ -429,7 +1628,7 @@ mod tests {
         // 0x40000004 - 4 + 0x3000000
         // 0x40000000 + 0x3000000
         // 0x43000000
-        auipc_inst.execute(&mut cpu);
+        auipc_inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(5), 0x43000000);
 
         // manually increment PC since no fetching here
@@ -447,7 +1646,7 @@ mod tests {
                 imm: -0x400i32 as u32
             }
         );
-        jarl_inst.execute(&mut cpu);
+        jarl_inst.execute(&mut cpu).unwrap();
         assert_eq!(cpu.regs.read(10), 0x40000008);
         assert_eq!(cpu.pc.get(), 0x42fffc00);
     }
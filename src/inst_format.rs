@@ -56,6 +56,7 @@ macro_rules! get_bits {
 //  - Combined Function Fields: funct7 and funct3 together specify the exact operation.
 //  - Consistent Field Placement: rs1, rs2, and rd fields are in the same
 //    position as in other formats.
+#[derive(Clone, Debug, PartialEq)]
 pub struct RFormat {
     pub rd: usize,
     pub funct3: usize,
@@ -74,6 +75,55 @@ impl RFormat {
             funct7: get_bits!(raw_inst, 25, 31),
         }
     }
+
+    // Packs the fields back into an instruction word, the inverse of `new`.
+    pub fn encode(&self, opcode: u32) -> u32 {
+        ((self.funct7 as u32) << 25)
+            | ((self.rs2 as u32) << 20)
+            | ((self.rs1 as u32) << 15)
+            | ((self.funct3 as u32) << 12)
+            | ((self.rd as u32) << 7)
+            | opcode
+    }
+}
+
+// R4-type (Register, 4 operands):
+// Used by the fused multiply-add instructions (F/D extensions),
+// which have a third source register instead of funct7.
+//
+// 31    27 26  25 24    20 19    15 14     12 11      7 6      0
+// +-------+------+--------+--------+---------+---------+-------+
+// |  rs3  |funct2|  rs2   |  rs1   |  funct3 |   rd    | opcode|
+// +-------+------+--------+--------+---------+---------+-------+
+//
+// rs3 (5 bits): Third source register operand.
+// funct2 (2 bits): Operand format (00 = single, 01 = double precision).
+// rs2 (5 bits): Second source register operand.
+// rs1 (5 bits): First source register operand.
+// funct3 (3 bits): Rounding mode (rm).
+// rd (5 bits): Destination register.
+// opcode (7 bits): Operation code (FMADD, FMSUB, FNMSUB, FNMADD).
+#[derive(Clone, Debug, PartialEq)]
+pub struct R4Format {
+    pub rd: usize,
+    pub funct3: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct2: usize,
+    pub rs3: usize,
+}
+
+impl R4Format {
+    pub fn new(raw_inst: u32) -> Self {
+        Self {
+            rd: get_bits!(raw_inst, 7, 11),
+            funct3: get_bits!(raw_inst, 12, 14),
+            rs1: get_bits!(raw_inst, 15, 19),
+            rs2: get_bits!(raw_inst, 20, 24),
+            funct2: get_bits!(raw_inst, 25, 26),
+            rs3: get_bits!(raw_inst, 27, 31),
+        }
+    }
 }
 
 // I-type (Immediate):
@@ -91,6 +141,7 @@ impl RFormat {
 // funct3: 3-bit function code (bits 14:12).
 // rd: 5-bit destination register (bits 11:7).
 // opcode: 7-bit opcode (bits 6:0).
+#[derive(Clone, Debug, PartialEq)]
 pub struct IFormat {
     pub rd: usize,
     pub funct3: usize,
@@ -108,6 +159,15 @@ impl IFormat {
             imm: get_bits!(raw_inst, 20, 31, i32) as u32,
         }
     }
+
+    // only imm[11:0] is encoded
+    pub fn encode(&self, opcode: u32) -> u32 {
+        ((self.imm & 0xfff) << 20)
+            | ((self.rs1 as u32) << 15)
+            | ((self.funct3 as u32) << 12)
+            | ((self.rd as u32) << 7)
+            | opcode
+    }
 }
 
 // S-type (Store):
@@ -127,6 +187,7 @@ impl IFormat {
 // funct3: 3-bit function code specifying store type (bits 14:12).
 // imm[4:0]: Lower 5 bits of 12-bit immediate (bits 11:7).
 // opcode: 7-bit operation code (bits 6:0).
+#[derive(Clone, Debug, PartialEq)]
 pub struct SFormat {
     pub funct3: usize,
     pub rs1: usize,
@@ -147,6 +208,16 @@ impl SFormat {
             imm,
         }
     }
+
+    // splits the immediate back into imm[11:5] and imm[4:0]
+    pub fn encode(&self, opcode: u32) -> u32 {
+        (get_bits!(self.imm, 5, 11, u32) << 25)
+            | ((self.rs2 as u32) << 20)
+            | ((self.rs1 as u32) << 15)
+            | ((self.funct3 as u32) << 12)
+            | (get_bits!(self.imm, 0, 4, u32) << 7)
+            | opcode
+    }
 }
 
 // B-type (Branch):
@@ -167,6 +238,7 @@ impl SFormat {
 // imm[4:1]: Lower 4 bits of immediate (bits 11:8).
 // imm[11]: Second-highest bit of immediate (bit 7).
 // opcode: 6-bit operation code (bits 6:0).
+#[derive(Clone, Debug, PartialEq)]
 pub struct BFormat {
     pub funct3: usize,
     pub rs1: usize,
@@ -205,6 +277,19 @@ impl BFormat {
             imm,
         }
     }
+
+    // Scatters the offset back into the positions `new` collects it from.
+    // imm[0] isn't encoded, so odd offsets are rounded down.
+    pub fn encode(&self, opcode: u32) -> u32 {
+        (get_bits!(self.imm, 12, 12, u32) << 31)
+            | (get_bits!(self.imm, 5, 10, u32) << 25)
+            | ((self.rs2 as u32) << 20)
+            | ((self.rs1 as u32) << 15)
+            | ((self.funct3 as u32) << 12)
+            | (get_bits!(self.imm, 1, 4, u32) << 8)
+            | (get_bits!(self.imm, 11, 11, u32) << 7)
+            | opcode
+    }
 }
 
 // TODO: why is immediate split into parts?
@@ -224,6 +309,7 @@ impl BFormat {
 // imm[19:12]: Upper 8 bits of immediate (bits 19:12).
 // rd: 5-bit destination register (bits 11:7).
 // opcode: 7-bit operation code (bits 6:0).
+#[derive(Clone, Debug, PartialEq)]
 pub struct JFormat {
     pub rd: usize,
     pub imm: u32,
@@ -244,6 +330,16 @@ impl JFormat {
             imm,
         }
     }
+
+    // imm[20|10:1|11|19:12], imm[0] isn't encoded like for branches
+    pub fn encode(&self, opcode: u32) -> u32 {
+        (get_bits!(self.imm, 20, 20, u32) << 31)
+            | (get_bits!(self.imm, 1, 10, u32) << 21)
+            | (get_bits!(self.imm, 11, 11, u32) << 20)
+            | (get_bits!(self.imm, 12, 19, u32) << 12)
+            | ((self.rd as u32) << 7)
+            | opcode
+    }
 }
 
 // U-type (Upper Immediate):
@@ -258,6 +354,7 @@ impl JFormat {
 // imm[31:12]: 20-bit immediate value (bits 31:12).
 // rd: 5-bit destination register (bits 11:7).
 // opcode: 7-bit operation code (bits 6:0).
+#[derive(Clone, Debug, PartialEq)]
 pub struct UFormat {
     pub rd: usize,
     pub imm: u32,
@@ -270,6 +367,11 @@ impl UFormat {
             imm: get_bits!(raw_inst, 12, 31, i32) as u32,
         }
     }
+
+    // `imm` holds imm[31:12] in its low 20 bits
+    pub fn encode(&self, opcode: u32) -> u32 {
+        ((self.imm & 0xfffff) << 12) | ((self.rd as u32) << 7) | opcode
+    }
 }
 
 #[cfg(test)]
@@ -291,6 +393,34 @@ mod tests {
         assert_eq!(0b0011, get_bits!(n, 10, 13, i32));
     }
 
+    #[test]
+    fn parse_r4() {
+        // fmadd.s f1, f2, f3, f4, dyn
+        assert_eq!(
+            R4Format::new(0x203170c3),
+            R4Format {
+                rd: 1,
+                funct3: 0b111,
+                rs1: 2,
+                rs2: 3,
+                funct2: 0b00,
+                rs3: 4,
+            }
+        );
+        // fmadd.d f31, f30, f29, f28, rne
+        assert_eq!(
+            R4Format::new(0xe3df0fc3),
+            R4Format {
+                rd: 31,
+                funct3: 0b000,
+                rs1: 30,
+                rs2: 29,
+                funct2: 0b01,
+                rs3: 28,
+            }
+        );
+    }
+
     #[test]
     fn parse_cond_branch_imm() {
         // bge x0, x0, -12
@@ -304,4 +434,43 @@ mod tests {
             -12
         )
     }
+
+    #[test]
+    fn encode_cond_branch_imm() {
+        // bge x0, x0, -12
+        let bge = BFormat {
+            funct3: 0x5,
+            rs1: 0,
+            rs2: 0,
+            imm: -12i32 as u32,
+        };
+        assert_eq!(bge.encode(0b1100011), 0b1111_1110_0000_0000_0101_1010_1110_0011);
+        // every immediate bit lands where `new` reads it from
+        for offset in [-4096, -2, 2, 0x7fe, 0x800, 0xffe, 0x2aa, -0x556] {
+            let b = BFormat {
+                funct3: 0x1,
+                rs1: 3,
+                rs2: 4,
+                imm: offset as u32,
+            };
+            assert_eq!(BFormat::new(b.encode(0b1100011)), b);
+        }
+    }
+
+    #[test]
+    fn encode_jump_imm() {
+        for offset in [-0x100000, -2, 0x7fe, 0x800, 0xff000, 0xffffe, 0x55554] {
+            let j = JFormat {
+                rd: 1,
+                imm: offset as u32,
+            };
+            assert_eq!(JFormat::new(j.encode(0b1101111)), j);
+        }
+        // jal x0, -2048
+        let j = JFormat {
+            rd: 0,
+            imm: -2048i32 as u32,
+        };
+        assert_eq!(j.encode(0b1101111), 0x801ff06f);
+    }
 }
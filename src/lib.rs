@@ -0,0 +1,21 @@
+pub mod asm;
+pub mod block;
+pub mod cfg;
+pub mod cpu;
+pub mod device;
+pub mod disasm;
+pub mod elf;
+pub mod error;
+pub mod inst;
+pub mod inst_format;
+pub mod memory;
+pub mod mmu;
+pub mod pc;
+pub mod pipeline;
+pub mod regs;
+pub mod rng;
+pub mod sandbox;
+pub mod symbols;
+pub mod syscall;
+#[cfg(feature = "serde")]
+pub mod trace;
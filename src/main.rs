@@ -1,11 +1,3 @@
-mod cpu;
-mod error;
-mod inst;
-mod inst_format;
-mod memory;
-mod pc;
-mod regs;
-
 fn main() {
     println!("Hello, world!");
 }
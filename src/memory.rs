@@ -1,9 +1,10 @@
+use crate::error::Error;
 use crate::inst::*;
 
 // RAM size
 pub const MEM_SIZE: usize = 1024 * 128;
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub enum Size {
     // 8 bit
     Byte = 1,
@@ -13,6 +14,24 @@ pub enum Size {
     Word = 4,
 }
 
+impl Size {
+    // checks whether `addr` is a multiple of the access size
+    pub fn is_aligned(self, addr: u32) -> bool {
+        addr.is_multiple_of(self as u32)
+    }
+
+    // Access size encoded in funct3[1:0] of loads and stores (funct3[2] marks
+    // unsigned loads). 3 would be a doubleword, which only exists on RV64.
+    pub fn from_funct3(funct3: usize) -> Option<Size> {
+        match funct3 {
+            0 => Some(Size::Byte),
+            1 => Some(Size::HalfWord),
+            2 => Some(Size::Word),
+            _ => None,
+        }
+    }
+}
+
 impl From<LoadIInst> for Size {
     fn from(value: LoadIInst) -> Self {
         match value {
@@ -23,6 +42,16 @@ impl From<LoadIInst> for Size {
     }
 }
 
+impl From<Size> for SInst {
+    fn from(value: Size) -> Self {
+        match value {
+            Size::Byte => SInst::SB,
+            Size::HalfWord => SInst::SH,
+            Size::Word => SInst::SW,
+        }
+    }
+}
+
 impl From<SInst> for Size {
     fn from(value: SInst) -> Self {
         match value {
@@ -33,6 +62,16 @@ impl From<SInst> for Size {
     }
 }
 
+// What to do with loads/stores whose address
+// isn't a multiple of the access size.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MisalignedAccess {
+    // performs the access as if it was aligned
+    Emulate,
+    // raises load/store-address-misaligned exception
+    Trap,
+}
+
 macro_rules! read_mem {
     ($ty:ty, $mem:expr, $from:expr, $to:expr) => {
         // Little-Endian (LE)
@@ -43,34 +82,292 @@ macro_rules! read_mem {
     };
 }
 
-pub struct Memory([u8; MEM_SIZE]);
+// 64-bit FNV-1a, `hash` is either `FNV_OFFSET` or the hash of the preceding bytes.
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+pub struct Memory {
+    // `MEM_SIZE` bytes unless created with `from_buffer`
+    bytes: Box<[u8]>,
+    // which bytes were ever written or loaded, if tracking is enabled
+    initialized: Option<Vec<bool>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Memory {
     pub fn new() -> Self {
-        Memory([0; MEM_SIZE])
+        Self::from_buffer(vec![0; MEM_SIZE].into_boxed_slice())
     }
 
-    pub fn read(&self, from: u32, size: Size, is_unsigned: bool) -> u32 {
-        let to = from + size.clone() as u32;
-        match (size, is_unsigned) {
-            (Size::Byte, true) => read_mem!(u8, self.0, from, to),
-            (Size::Byte, false) => read_mem!(i8, self.0, from, to),
-            (Size::HalfWord, true) => read_mem!(u16, self.0, from, to),
-            (Size::HalfWord, false) => read_mem!(i16, self.0, from, to),
-            (Size::Word, _) => read_mem!(u32, self.0, from, to),
+    // Uses `buf` as the memory without copying it, e.g. to persist guest
+    // memory or to run with a different size. Accesses are bounds checked
+    // against its length.
+    pub fn from_buffer(buf: Box<[u8]>) -> Self {
+        Memory {
+            bytes: buf,
+            initialized: None,
         }
     }
 
-    pub fn write(&mut self, from: u32, size: Size, val: u32) {
-        let slice = val.to_le_bytes();
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Starts tracking which bytes hold written data. Everything
+    // is considered uninitialized until the next write or program load.
+    pub fn track_initialized(&mut self) {
+        self.initialized = Some(vec![false; self.len()]);
+    }
+
+    // Always true if tracking isn't enabled.
+    pub fn is_initialized(&self, from: u32, size: Size) -> bool {
         let from = from as usize;
+        match &self.initialized {
+            Some(initialized) => initialized
+                .get(from..from + size as usize)
+                .is_some_and(|bytes| bytes.iter().all(|&b| b)),
+            None => true,
+        }
+    }
+
+    fn mark_initialized(&mut self, from: usize, to: usize) {
+        if let Some(initialized) = &mut self.initialized {
+            initialized[from..to].fill(true);
+        }
+    }
+
+    // Accesses are bounds checked as a whole, so an address
+    // that ends up outside memory produces an access fault
+    // instead of a panic.
+    pub fn read(&self, from: u32, size: Size, is_unsigned: bool) -> Result<u32, Error> {
+        let to = from as usize + size as usize;
+        if to > self.len() {
+            return Err(Error::LoadAccessFault(from, self.len()));
+        }
+        let val = match (size, is_unsigned) {
+            (Size::Byte, true) => read_mem!(u8, self.bytes, from, to),
+            (Size::Byte, false) => read_mem!(i8, self.bytes, from, to),
+            (Size::HalfWord, true) => read_mem!(u16, self.bytes, from, to),
+            (Size::HalfWord, false) => read_mem!(i16, self.bytes, from, to),
+            (Size::Word, _) => read_mem!(u32, self.bytes, from, to),
+        };
+        Ok(val)
+    }
+
+    pub fn write(&mut self, from: u32, size: Size, val: u32) -> Result<(), Error> {
+        let slice = val.to_le_bytes();
         let len = size as usize;
-        self.0[from..from + len].copy_from_slice(&slice[0..len])
+        let to = from as usize + len;
+        if to > self.len() {
+            return Err(Error::StoreAccessFault(from, self.len()));
+        }
+        self.bytes[from as usize..to].copy_from_slice(&slice[0..len]);
+        self.mark_initialized(from as usize, to);
+        Ok(())
+    }
+
+    // Sets `len` bytes starting at `from` to `val` (memset).
+    // Nothing is written if the range doesn't fit in memory.
+    pub fn fill(&mut self, from: u32, len: usize, val: u8) -> Result<(), Error> {
+        let to = (from as usize).saturating_add(len);
+        if to > self.len() {
+            return Err(Error::StoreAccessFault(from, self.len()));
+        }
+        self.bytes[from as usize..to].fill(val);
+        self.mark_initialized(from as usize, to);
+        Ok(())
+    }
+
+    pub fn zero(&mut self, from: u32, len: usize) -> Result<(), Error> {
+        self.fill(from, len, 0)
+    }
+
+    // Bulk versions of word `read`/`write`, the whole range is bounds checked at once.
+    // Words are little-endian like every other access, RISC-V has no big-endian mode here.
+    pub fn read_words(&self, from: u32, count: usize) -> Result<Vec<u32>, Error> {
+        let to = (from as usize).saturating_add(count.saturating_mul(4));
+        if to > self.len() {
+            return Err(Error::LoadAccessFault(from, self.len()));
+        }
+        let words = self.bytes[from as usize..to]
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(words)
+    }
+
+    pub fn write_words(&mut self, from: u32, words: &[u32]) -> Result<(), Error> {
+        let to = (from as usize).saturating_add(words.len() * 4);
+        if to > self.len() {
+            return Err(Error::StoreAccessFault(from, self.len()));
+        }
+        for (dst, word) in self.bytes[from as usize..to].chunks_exact_mut(4).zip(words) {
+            dst.copy_from_slice(&word.to_le_bytes());
+        }
+        self.mark_initialized(from as usize, to);
+        Ok(())
+    }
+
+    // Hash of the whole memory, for cheaply comparing two machine states.
+    pub fn checksum(&self) -> u64 {
+        fnv1a(FNV_OFFSET, &self.bytes)
+    }
+
+    // Overlays `bytes` at `from` and leaves the rest of the memory alone,
+    // e.g. for loading several segments. Nothing is written if they don't fit.
+    pub fn copy_in(&mut self, from: u32, bytes: &[u8]) -> Result<(), Error> {
+        let to = (from as usize).saturating_add(bytes.len());
+        if to > self.len() {
+            return Err(Error::StoreAccessFault(from, self.len()));
+        }
+        self.bytes[from as usize..to].copy_from_slice(bytes);
+        self.mark_initialized(from as usize, to);
+        Ok(())
+    }
+
+    // Loads program to start of the memory, the rest is zeroed.
+    // Use `copy_in` to keep what's already there.
+    // Anything that doesn't fit is cut off.
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        let len = program.len().min(self.len());
+        self.bytes[..len].copy_from_slice(&program[..len]);
+        self.bytes[len..].fill(0);
+        if let Some(initialized) = &mut self.initialized {
+            initialized.fill(false);
+            initialized[..len].fill(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_from_funct3() {
+        assert!(matches!(Size::from_funct3(0), Some(Size::Byte)));
+        assert!(matches!(Size::from_funct3(1), Some(Size::HalfWord)));
+        assert!(matches!(Size::from_funct3(2), Some(Size::Word)));
+        // ld/sd on RV64
+        assert!(Size::from_funct3(3).is_none());
+        // reserved for stores, lbu for loads (size comes from the low bits)
+        assert!(Size::from_funct3(4).is_none());
+        assert!(matches!(Size::from_funct3(4 & 0b11), Some(Size::Byte)));
+    }
+
+    #[test]
+    fn from_buffer() {
+        let mut buf = vec![0u8; 4096].into_boxed_slice();
+        buf[0x10] = 0xab;
+        let mut mem = Memory::from_buffer(buf);
+        assert_eq!(mem.len(), 4096);
+        assert_eq!(mem.read(0x10, Size::Byte, true).unwrap(), 0xab);
+        mem.write(0xffc, Size::Word, 0x12345678).unwrap();
+        assert_eq!(mem.as_bytes()[0xffc..], [0x78, 0x56, 0x34, 0x12]);
+        // bounds come from the buffer
+        assert!(matches!(mem.read(0x1000, Size::Byte, true), Err(Error::LoadAccessFault(..))));
+        assert!(matches!(
+            mem.write(0xffe, Size::Word, 0),
+            Err(Error::StoreAccessFault(..))
+        ));
+    }
+
+    #[test]
+    fn fill_range() {
+        let mut mem = Memory::new();
+        mem.fill(0x100, 100, 0xab).unwrap();
+        assert_eq!(mem.read(0xff, Size::Byte, true).unwrap(), 0);
+        assert!(mem.bytes[0x100..0x164].iter().all(|&b| b == 0xab));
+        assert_eq!(mem.read(0x164, Size::Byte, true).unwrap(), 0);
+
+        mem.zero(0x110, 4).unwrap();
+        assert_eq!(mem.read(0x110, Size::Word, true).unwrap(), 0);
+    }
+
+    #[test]
+    fn words_round_trip() {
+        let mut mem = Memory::new();
+        mem.write_words(0x102, &[0x12345678, 0x9abcdef0]).unwrap();
+        // little-endian byte order
+        assert_eq!(mem.bytes[0x102..0x10a], [0x78, 0x56, 0x34, 0x12, 0xf0, 0xde, 0xbc, 0x9a]);
+        assert_eq!(mem.read_words(0x102, 2).unwrap(), [0x12345678, 0x9abcdef0]);
+        assert!(matches!(
+            mem.read_words(MEM_SIZE as u32 - 4, 2),
+            Err(Error::LoadAccessFault(..))
+        ));
+        assert!(matches!(
+            mem.write_words(MEM_SIZE as u32 - 4, &[1, 2]),
+            Err(Error::StoreAccessFault(..))
+        ));
+    }
+
+    #[test]
+    fn checksum() {
+        let mut a = Memory::new();
+        let mut b = Memory::new();
+        a.write(0x100, Size::Word, 42).unwrap();
+        b.write(0x100, Size::Word, 42).unwrap();
+        assert_eq!(a.checksum(), b.checksum());
+        b.write(0x1ff00, Size::Byte, 1).unwrap();
+        assert_ne!(a.checksum(), b.checksum());
+    }
+
+    #[test]
+    fn track_initialized() {
+        let mut mem = Memory::new();
+        assert!(mem.is_initialized(0x100, Size::Word));
+        mem.track_initialized();
+        mem.load_program(vec![1, 2, 3]);
+        assert!(mem.is_initialized(0, Size::HalfWord));
+        assert!(!mem.is_initialized(2, Size::HalfWord));
+        mem.write(0x101, Size::HalfWord, 0).unwrap();
+        assert!(!mem.is_initialized(0x100, Size::HalfWord));
+        assert!(mem.is_initialized(0x101, Size::HalfWord));
+    }
+
+    #[test]
+    fn copy_in_overlays() {
+        let mut mem = Memory::new();
+        mem.copy_in(0x1000, &[1, 2, 3]).unwrap();
+        mem.copy_in(0x10, &[0xaa, 0xbb]).unwrap();
+        assert_eq!(mem.bytes[0x1000..0x1004], [1, 2, 3, 0]);
+        assert_eq!(mem.bytes[0x10..0x12], [0xaa, 0xbb]);
+        assert!(matches!(
+            mem.copy_in(MEM_SIZE as u32 - 1, &[1, 2]),
+            Err(Error::StoreAccessFault(..))
+        ));
+        // load_program still replaces everything
+        mem.load_program(vec![9]);
+        assert_eq!(mem.bytes[0x1000], 0);
     }
 
-    // loads program to start of the memory
-    pub fn load_program(&mut self, mut program: Vec<u8>) {
-        program.resize_with(MEM_SIZE, || 0);
-        self.0 = program.try_into().unwrap();
+    #[test]
+    fn fill_out_of_range() {
+        let mut mem = Memory::new();
+        let from = MEM_SIZE as u32 - 50;
+        assert!(matches!(
+            mem.fill(from, 100, 0xab),
+            Err(Error::StoreAccessFault(addr, _)) if addr == from
+        ));
+        // nothing is written
+        assert!(mem.bytes[from as usize..].iter().all(|&b| b == 0));
     }
 }
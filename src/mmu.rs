@@ -0,0 +1,319 @@
+// Sv32 virtual memory: 32-bit virtual addresses are translated to physical
+// ones through a two-level page table with 4 KiB pages (and 4 MiB megapages).
+// There are no privilege modes, so once `satp` enables paging every access
+// is translated as if running in S-mode, and the U bit isn't checked.
+use crate::error::Error;
+use crate::get_bits;
+use crate::memory::{Memory, Size};
+
+pub const PAGE_SIZE: u32 = 4096;
+
+// satp CSR number
+pub const SATP: u32 = 0x180;
+
+// PTE flags
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, addr: u32) -> Error {
+        match self {
+            Access::Fetch => Error::InstructionPageFault(addr),
+            Access::Load => Error::LoadPageFault(addr),
+            Access::Store => Error::StorePageFault(addr),
+        }
+    }
+}
+
+// satp: MODE[31] | ASID[30:22] | PPN[21:0]
+// MODE 0 is Bare (no translation), 1 is Sv32.
+pub fn is_enabled(satp: u32) -> bool {
+    satp >> 31 == 1
+}
+
+// Whether a leaf PTE allows the access.
+// The A and D bits aren't updated by the walk, an access to a page
+// that doesn't have them set yet faults like on hardware without Svadu.
+fn permits(pte: u32, access: Access) -> bool {
+    let allowed = match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0,
+        Access::Store => pte & PTE_W != 0,
+    };
+    allowed && pte & PTE_A != 0 && (access != Access::Store || pte & PTE_D != 0)
+}
+
+// Page table walk as described in the privileged spec (4.3.2).
+pub fn translate(mem: &Memory, satp: u32, vaddr: u32, access: Access) -> Result<u32, Error> {
+    walk(mem, satp, vaddr, access).map(|(_, paddr)| paddr)
+}
+
+// Returns the leaf PTE together with the physical address.
+fn walk(mem: &Memory, satp: u32, vaddr: u32, access: Access) -> Result<(u32, u32), Error> {
+    let fault = access.page_fault(vaddr);
+    // physical addresses are 34 bits, ours have to fit in 32
+    let phys = |ppn: u32, offset: u32| {
+        u32::try_from(((ppn as u64) << 12) + offset as u64).map_err(|_| access.page_fault(vaddr))
+    };
+    // VPN[1] | VPN[0] | page offset
+    let vpn = [get_bits!(vaddr, 12, 21, u32), get_bits!(vaddr, 22, 31, u32)];
+    let mut table = get_bits!(satp, 0, 21, u32);
+    for level in [1, 0] {
+        // a table outside memory faults the access being translated,
+        // not as a load of the PTE
+        let pte = mem
+            .read(phys(table, vpn[level] * 4)?, Size::Word, true)
+            .map_err(|_| access.page_fault(vaddr))?;
+        // W without R is reserved
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(fault);
+        }
+        // PPN[1] | PPN[0] | RSW | flags
+        let ppn = pte >> 10;
+        if pte & (PTE_R | PTE_X) == 0 {
+            // pointer to the next level
+            table = ppn;
+            continue;
+        }
+        if !permits(pte, access) {
+            return Err(fault);
+        }
+        let paddr = match level {
+            // a megapage has to be aligned to 4 MiB
+            1 if get_bits!(ppn, 0, 9) != 0 => return Err(fault),
+            1 => phys(ppn, get_bits!(vaddr, 0, 21, u32))?,
+            _ => phys(ppn, vaddr % PAGE_SIZE)?,
+        };
+        return Ok((pte, paddr));
+    }
+    // the second level isn't a leaf either
+    Err(fault)
+}
+
+pub const TLB_SIZE: usize = 64;
+
+// Cached translation of a 4 KiB page (megapages are cached page by page).
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    vpn: u32,
+    asid: u32,
+    pte: u32,
+    // physical address of the page
+    page: u32,
+}
+
+// Direct-mapped translation lookaside buffer, indexed by the low bits of the VPN
+// xor the ASID, so the same page of two address spaces doesn't share an entry.
+// It isn't kept coherent with the page tables: like on hardware, the program
+// has to flush it after changing them (`sfence.vma`), and writes to `satp` flush it too.
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    // page table walks done on misses
+    walks: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Tlb {
+            entries: vec![None; TLB_SIZE],
+            walks: 0,
+        }
+    }
+
+    pub fn walks(&self) -> u64 {
+        self.walks
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+
+    // Flushes the entries of the page containing `vaddr` that belong
+    // to `asid`, `None` matches any page or address space (`sfence.vma`).
+    pub fn flush_matching(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        let vpn = vaddr.map(|vaddr| vaddr / PAGE_SIZE);
+        for slot in &mut self.entries {
+            if let Some(entry) = slot
+                && vpn.is_none_or(|vpn| vpn == entry.vpn)
+                && asid.is_none_or(|asid| asid == entry.asid)
+            {
+                *slot = None;
+            }
+        }
+    }
+
+    // Same as `mmu::translate`, but walks the page table only on a miss.
+    // A hit that doesn't permit the access is walked again in case the PTE changed.
+    pub fn translate(
+        &mut self,
+        mem: &Memory,
+        satp: u32,
+        vaddr: u32,
+        access: Access,
+    ) -> Result<u32, Error> {
+        let vpn = vaddr / PAGE_SIZE;
+        let asid = get_bits!(satp, 22, 30, u32);
+        let slot = (vpn ^ asid) as usize % TLB_SIZE;
+        if let Some(entry) = self.entries[slot]
+            && entry.vpn == vpn
+            && entry.asid == asid
+            && permits(entry.pte, access)
+        {
+            return Ok(entry.page | (vaddr % PAGE_SIZE));
+        }
+        self.walks += 1;
+        let (pte, paddr) = walk(mem, satp, vaddr, access)?;
+        self.entries[slot] = Some(TlbEntry {
+            vpn,
+            asid,
+            pte,
+            page: paddr & !(PAGE_SIZE - 1),
+        });
+        Ok(paddr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MEM_SIZE;
+
+    const ROOT: u32 = 0x10000;
+    const LEAVES: u32 = 0x11000;
+    const SATP_SV32: u32 = (1 << 31) | (ROOT / PAGE_SIZE);
+    const RWX: u32 = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+
+    fn pte(paddr: u32, flags: u32) -> u32 {
+        ((paddr / PAGE_SIZE) << 10) | flags
+    }
+
+    // Identity maps the first 128 KiB (everything but the page tables are
+    // read-write-execute) and maps the page at 0x5000 to 0x3000.
+    // The page at 0x6000 isn't mapped.
+    fn page_table() -> Memory {
+        let mut mem = Memory::new();
+        mem.write(ROOT, Size::Word, pte(LEAVES, PTE_V)).unwrap();
+        for page in 0..32 {
+            let flags = if page >= ROOT / PAGE_SIZE { RWX & !PTE_W & !PTE_X } else { RWX };
+            mem.write(LEAVES + page * 4, Size::Word, pte(page * PAGE_SIZE, flags)).unwrap();
+        }
+        mem.write(LEAVES + 5 * 4, Size::Word, pte(0x3000, RWX)).unwrap();
+        mem.write(LEAVES + 6 * 4, Size::Word, 0).unwrap();
+        mem
+    }
+
+    #[test]
+    fn bare_mode() {
+        assert!(!is_enabled(ROOT / PAGE_SIZE));
+        assert!(is_enabled(SATP_SV32));
+    }
+
+    #[test]
+    fn translates_pages() {
+        let mem = page_table();
+        let translate = |vaddr, access| translate(&mem, SATP_SV32, vaddr, access);
+        assert_eq!(translate(0x1234, Access::Load).unwrap(), 0x1234);
+        assert_eq!(translate(0x5678, Access::Store).unwrap(), 0x3678);
+        assert!(matches!(translate(0x6000, Access::Load), Err(Error::LoadPageFault(0x6000))));
+        // beyond the second level table's mapped entries
+        assert!(matches!(translate(0x20000, Access::Fetch), Err(Error::InstructionPageFault(_))));
+        // the page tables are read-only
+        assert_eq!(translate(ROOT, Access::Load).unwrap(), ROOT);
+        assert!(matches!(translate(ROOT, Access::Store), Err(Error::StorePageFault(ROOT))));
+        // root table outside of memory
+        let satp = (1 << 31) | (MEM_SIZE as u32 / PAGE_SIZE);
+        let translate = |vaddr, access| super::translate(&mem, satp, vaddr, access);
+        let fetch = translate(0x1234, Access::Fetch);
+        assert!(matches!(fetch, Err(Error::InstructionPageFault(0x1234))));
+        assert!(matches!(translate(0x1234, Access::Load), Err(Error::LoadPageFault(0x1234))));
+        assert!(matches!(translate(0x1234, Access::Store), Err(Error::StorePageFault(0x1234))));
+    }
+
+    #[test]
+    fn translates_megapages() {
+        let mut mem = page_table();
+        // VPN[1] = 1 maps to physical address 0
+        mem.write(ROOT + 4, Size::Word, pte(0, RWX)).unwrap();
+        assert_eq!(translate(&mem, SATP_SV32, 0x400010, Access::Load).unwrap(), 0x10);
+        // misaligned megapage
+        mem.write(ROOT + 4, Size::Word, pte(0x1000, RWX)).unwrap();
+        assert!(matches!(
+            translate(&mem, SATP_SV32, 0x400010, Access::Load),
+            Err(Error::LoadPageFault(0x400010))
+        ));
+    }
+
+    #[test]
+    fn tlb_caches_translations() {
+        let mut mem = page_table();
+        let mut tlb = Tlb::new();
+        assert_eq!(tlb.translate(&mem, SATP_SV32, 0x5004, Access::Load).unwrap(), 0x3004);
+        assert_eq!(tlb.translate(&mem, SATP_SV32, 0x5ffc, Access::Store).unwrap(), 0x3ffc);
+        assert_eq!(tlb.walks(), 1);
+        // stale until flushed
+        mem.write(LEAVES + 5 * 4, Size::Word, pte(0x4000, RWX)).unwrap();
+        assert_eq!(tlb.translate(&mem, SATP_SV32, 0x5004, Access::Load).unwrap(), 0x3004);
+        tlb.flush();
+        assert_eq!(tlb.translate(&mem, SATP_SV32, 0x5004, Access::Load).unwrap(), 0x4004);
+        assert_eq!(tlb.walks(), 2);
+        // different address space
+        tlb.translate(&mem, SATP_SV32 | (1 << 22), 0x5004, Access::Load).unwrap();
+        assert_eq!(tlb.walks(), 3);
+        // faults aren't cached
+        assert!(tlb.translate(&mem, SATP_SV32, 0x6000, Access::Load).is_err());
+        assert!(tlb.translate(&mem, SATP_SV32, 0x6000, Access::Load).is_err());
+        assert_eq!(tlb.walks(), 5);
+    }
+
+    #[test]
+    fn tlb_flush_matching() {
+        let mem = page_table();
+        let mut tlb = Tlb::new();
+        let other_asid = SATP_SV32 | (1 << 22);
+        for satp in [SATP_SV32, other_asid] {
+            for vaddr in [0x1000, 0x5000] {
+                tlb.translate(&mem, satp, vaddr, Access::Load).unwrap();
+            }
+        }
+        assert_eq!(tlb.walks(), 4);
+        // one page of one address space
+        tlb.flush_matching(Some(0x5abc), Some(0));
+        tlb.translate(&mem, other_asid, 0x5000, Access::Load).unwrap();
+        assert_eq!(tlb.walks(), 4);
+        tlb.translate(&mem, SATP_SV32, 0x5000, Access::Load).unwrap();
+        assert_eq!(tlb.walks(), 5);
+        // a whole address space
+        tlb.flush_matching(None, Some(1));
+        tlb.translate(&mem, SATP_SV32, 0x1000, Access::Load).unwrap();
+        assert_eq!(tlb.walks(), 5);
+        tlb.translate(&mem, other_asid, 0x1000, Access::Load).unwrap();
+        assert_eq!(tlb.walks(), 6);
+    }
+
+    #[test]
+    fn accessed_and_dirty_bits() {
+        let mut mem = page_table();
+        mem.write(LEAVES + 4, Size::Word, pte(0x1000, RWX & !PTE_D)).unwrap();
+        assert!(translate(&mem, SATP_SV32, 0x1000, Access::Load).is_ok());
+        assert!(translate(&mem, SATP_SV32, 0x1000, Access::Store).is_err());
+        mem.write(LEAVES + 4, Size::Word, pte(0x1000, RWX & !PTE_A)).unwrap();
+        assert!(translate(&mem, SATP_SV32, 0x1000, Access::Load).is_err());
+    }
+}
@@ -1,31 +1,103 @@
 use crate::error::Error;
-use crate::memory::MEM_SIZE;
 
-pub struct ProgramCounter(u32);
+pub struct ProgramCounter {
+    // address of the next instruction to fetch
+    next: u32,
+    // address of the instruction being executed
+    current: u32,
+}
+
+impl Default for ProgramCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl ProgramCounter {
     pub fn new() -> Self {
-        ProgramCounter(0)
+        ProgramCounter {
+            next: 0,
+            current: 0,
+        }
     }
 
+    // Address of the next instruction. While an instruction executes,
+    // it points past it (see `inc`).
     pub fn get(&self) -> u32 {
-        self.0
+        self.next
     }
 
     pub fn set(&mut self, addr: u32) {
-        self.0 = addr
-    }
-
-    // Increments the program counter and returns
-    // the PC before it was incremented (AKA i++).
-    pub fn inc(&mut self) -> Result<u32, Error> {
-        let pc = self.0;
-        // All base instructions in RISC-V are 32 bits (4 bytes) long.
-        // The PC tracks byte addresses, so each sequential instruction is plus 4 bytes.
-        self.0 += 4;
-        if self.0 > MEM_SIZE as u32 {
-            return Err(Error::InvalidPC(pc, MEM_SIZE));
+        self.next = addr
+    }
+
+    // Address of the instruction being executed,
+    // i.e. what `get` returned before the last `inc`.
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // Increments the program counter by the length of the fetched
+    // instruction and returns the PC before it was incremented (AKA i++).
+    // The instruction has to end within `mem_size` bytes.
+    pub fn inc(&mut self, len: u32, mem_size: usize) -> Result<u32, Error> {
+        let pc = self.next;
+        // All base instructions in RISC-V are 32 bits (4 bytes) long,
+        // compressed (RVC) instructions are 16 bits (2 bytes) long.
+        // The PC tracks byte addresses, so each sequential instruction is plus `len` bytes.
+        match pc.checked_add(len) {
+            Some(next) if next as usize <= mem_size => self.advance(len),
+            _ => return Err(Error::InvalidPC(pc, mem_size)),
         }
         Ok(pc)
     }
+
+    // Same as `inc`, but without checking the address,
+    // for instructions that aren't fetched from memory.
+    pub fn advance(&mut self, len: u32) {
+        self.current = self.next;
+        self.next = self.next.wrapping_add(len);
+    }
+
+    // Jumps by `offset` bytes relative to the current instruction, which
+    // is how branch and jump offsets are encoded. The PC already points
+    // past the instruction, so adding the offset to it would be off by its length.
+    pub fn rel_jump(&mut self, offset: i32) {
+        self.next = self.current.wrapping_add_signed(offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MEM_SIZE;
+
+    #[test]
+    fn rel_jump_forward() {
+        let mut pc = ProgramCounter::new();
+        pc.set(0x100);
+        assert_eq!(pc.inc(4, MEM_SIZE).unwrap(), 0x100);
+        pc.rel_jump(0x20);
+        assert_eq!(pc.get(), 0x120);
+        assert_eq!(pc.current(), 0x100);
+    }
+
+    #[test]
+    fn rel_jump_backward() {
+        let mut pc = ProgramCounter::new();
+        pc.set(0x100);
+        // compressed instruction
+        pc.inc(2, MEM_SIZE).unwrap();
+        pc.rel_jump(-0x10);
+        assert_eq!(pc.get(), 0xf0);
+    }
+
+    #[test]
+    fn inc_out_of_memory() {
+        let mut pc = ProgramCounter::new();
+        pc.set(0xffe);
+        assert!(matches!(pc.inc(4, 0x1000), Err(Error::InvalidPC(0xffe, 0x1000))));
+        assert_eq!(pc.get(), 0xffe);
+        assert_eq!(pc.inc(2, 0x1000).unwrap(), 0xffe);
+    }
 }
@@ -0,0 +1,59 @@
+// Timing model of a classic 5-stage in-order pipeline
+// (fetch, decode, execute, memory, writeback) with forwarding.
+// It only counts the stall cycles the executed instructions would cause,
+// execution itself isn't affected.
+use crate::inst::Inst;
+
+// Cycles lost to a taken branch or a jump, which is resolved
+// in the execute stage while the next two instructions were
+// already fetched assuming it's not taken.
+pub const BRANCH_PENALTY: u64 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PipelineStats {
+    pub instructions: u64,
+    // a load followed by an instruction using its result,
+    // which even with forwarding has to wait a cycle
+    pub load_use_stalls: u64,
+    // cycles flushed after taken branches and jumps
+    pub control_stalls: u64,
+}
+
+impl PipelineStats {
+    pub fn stalls(&self) -> u64 {
+        self.load_use_stalls + self.control_stalls
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    stats: PipelineStats,
+    // destination register of the previous instruction if it was a load
+    pending_load: Option<usize>,
+}
+
+impl Pipeline {
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats
+    }
+
+    // Called before `inst` executes.
+    pub fn issue(&mut self, inst: &Inst) {
+        self.stats.instructions += 1;
+        if let Some(rd) = self.pending_load.take()
+            && inst.reads().contains(&rd)
+        {
+            self.stats.load_use_stalls += 1;
+        }
+        if inst.is_load() {
+            self.pending_load = inst.writes();
+        }
+    }
+
+    // Called after a branch or jump executed.
+    pub fn resolve(&mut self, taken: bool) {
+        if taken {
+            self.stats.control_stalls += BRANCH_PENALTY;
+        }
+    }
+}
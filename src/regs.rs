@@ -1,13 +1,26 @@
-use crate::memory::MEM_SIZE;
+use crate::error::Error;
+
+// Obviously wrong value that uninitialized registers
+// are filled with to catch reads before writes.
+pub const POISON: u32 = 0xAAAAAAAA;
 
 pub struct Registers([u32; 32]);
 
 impl Registers {
-    pub fn new() -> Self {
+    // The stack grows down from `stack_top`, usually the end of memory.
+    pub fn new(stack_top: u32) -> Self {
         let mut regs = Registers([0; 32]);
         // initializes stack pointer to the top of the stack
         // `x2` register is SP (stack pointer). Points to the top of the stack.
-        regs.0[2] = MEM_SIZE as u32;
+        regs.0[2] = stack_top;
+        regs
+    }
+
+    // Same as `new`, but every register except `x0` and SP holds `POISON`.
+    pub fn poisoned(stack_top: u32) -> Self {
+        let mut regs = Registers([POISON; 32]);
+        regs.0[0] = 0;
+        regs.0[2] = stack_top;
         regs
     }
 
@@ -28,4 +41,67 @@ impl Registers {
         }
         self.0[reg] = val;
     }
+
+    // Non-panicking versions of `read`/`write` for host tools
+    // (debugger, REPL) that take register indices from the user.
+    pub fn try_read(&self, reg: usize) -> Result<u32, Error> {
+        if reg >= 32 {
+            return Err(Error::InvalidRegister(reg));
+        }
+        Ok(self.read(reg))
+    }
+
+    pub fn try_write(&mut self, reg: usize, val: u32) -> Result<(), Error> {
+        if reg >= 32 {
+            return Err(Error::InvalidRegister(reg));
+        }
+        self.write(reg, val);
+        Ok(())
+    }
+
+    // Writes every register at once (e.g. GDB's `G` packet),
+    // the value for `x0` is ignored.
+    pub fn load_all(&mut self, vals: &[u32; 32]) {
+        self.0 = *vals;
+        self.0[0] = 0;
+    }
+
+    // Values of every register, `x0` first.
+    pub fn dump_all(&self) -> [u32; 32] {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_write_invalid_register() {
+        let mut regs = Registers::new(0x1000);
+        assert!(matches!(regs.try_write(40, 1), Err(Error::InvalidRegister(40))));
+        assert!(matches!(regs.try_read(32), Err(Error::InvalidRegister(32))));
+    }
+
+    #[test]
+    fn load_all() {
+        let mut regs = Registers::new(0x1000);
+        let vals: [u32; 32] = std::array::from_fn(|i| i as u32 + 100);
+        regs.load_all(&vals);
+        assert_eq!(regs.read(0), 0);
+        assert_eq!(regs.read(1), 101);
+        assert_eq!(regs.read(31), 131);
+        let dumped = regs.dump_all();
+        assert_eq!(dumped[0], 0);
+        assert_eq!(dumped[1..], vals[1..]);
+    }
+
+    #[test]
+    fn try_write_valid_register() {
+        let mut regs = Registers::new(0x1000);
+        regs.try_write(5, 1).unwrap();
+        regs.try_write(0, 1).unwrap();
+        assert_eq!(regs.try_read(5).unwrap(), 1);
+        assert_eq!(regs.try_read(0).unwrap(), 0);
+    }
 }
@@ -0,0 +1,28 @@
+// Seed used when none (or zero) is provided.
+pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+// Small xorshift64 PRNG. It's not suitable for cryptography
+// but it's deterministic, which keeps emulated programs reproducible.
+pub struct XorShift(u64);
+
+impl Default for XorShift {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED)
+    }
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // zero state never changes, so it's replaced with the default seed
+        XorShift(if seed == 0 { DEFAULT_SEED } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
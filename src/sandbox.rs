@@ -0,0 +1,24 @@
+// Guardrails for running untrusted programs.
+// Every limit is disabled (`None`) by default.
+#[derive(Clone, Copy, Default)]
+pub struct SandboxPolicy {
+    // total number of `ecall`s
+    pub max_syscalls: Option<u64>,
+    // total number of bytes written to stdout
+    pub max_stdout_bytes: Option<u64>,
+    // total number of executed instructions
+    pub max_cycles: Option<u64>,
+}
+
+// limit that was exceeded
+#[derive(Debug, PartialEq)]
+pub enum PolicyViolation {
+    Syscalls,
+    StdoutBytes,
+    Cycles,
+}
+
+// Checks whether `used` more units stay within `limit`.
+pub fn within(limit: Option<u64>, used: u64) -> bool {
+    limit.is_none_or(|limit| used <= limit)
+}
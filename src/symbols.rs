@@ -0,0 +1,61 @@
+// Address to name mapping for annotating addresses in traces and dumps,
+// e.g. for flat binaries which carry no symbols of their own.
+use std::collections::BTreeMap;
+
+#[derive(Default)]
+pub struct SymbolMap(BTreeMap<u32, String>);
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, addr: u32, name: impl Into<String>) {
+        self.0.insert(addr, name.into());
+    }
+
+    // Parses `nm` output: `<hex address> <type> <name>` per line.
+    // Lines without an address (undefined symbols) are skipped.
+    pub fn parse_nm(text: &str) -> Self {
+        let mut map = Self::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(addr), Some(_), Some(name)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if let Ok(addr) = u32::from_str_radix(addr, 16) {
+                map.insert(addr, name);
+            }
+        }
+        map
+    }
+
+    // Resolves `addr` to the nearest preceding symbol: `name` or `name+0x4`.
+    pub fn lookup(&self, addr: u32) -> Option<String> {
+        let (start, name) = self.0.range(..=addr).next_back()?;
+        match addr - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{name}+{offset:#x}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_nearest() {
+        let map = SymbolMap::parse_nm(
+            "00001000 T _start\n\
+             00001010 T main\n\
+             \x20        U printf\n\
+             00001040 t helper\n",
+        );
+        assert_eq!(map.lookup(0xfff), None);
+        assert_eq!(map.lookup(0x1000).as_deref(), Some("_start"));
+        assert_eq!(map.lookup(0x1014).as_deref(), Some("main+0x4"));
+        assert_eq!(map.lookup(0x2000).as_deref(), Some("helper+0xfc0"));
+    }
+}
@@ -0,0 +1,57 @@
+// returned (negated) by syscalls given an invalid file descriptor
+pub const EBADF: u32 = 9;
+// returned (negated) by syscalls given a buffer outside of memory
+pub const EFAULT: u32 = 14;
+// returned (negated) by syscalls the emulator doesn't implement
+pub const ENOSYS: u32 = 38;
+
+// Linux syscall numbers used by the RISC-V ABI.
+// The number is passed in `a7`, arguments in `a0`-`a5`
+// and the return value is placed in `a0`.
+#[derive(Debug, PartialEq)]
+pub enum LinuxSyscall {
+    Close,
+    Read,
+    Write,
+    Exit,
+    // terminates every thread, which is the same as `exit` with a single hart
+    ExitGroup,
+    Brk,
+    GetRandom,
+    // `clock_gettime64`, the only variant on RV32 with a 64-bit `time_t`
+    ClockGettime,
+    Unknown(u32),
+}
+
+impl From<u32> for LinuxSyscall {
+    fn from(value: u32) -> Self {
+        match value {
+            57 => LinuxSyscall::Close,
+            63 => LinuxSyscall::Read,
+            64 => LinuxSyscall::Write,
+            93 => LinuxSyscall::Exit,
+            94 => LinuxSyscall::ExitGroup,
+            214 => LinuxSyscall::Brk,
+            278 => LinuxSyscall::GetRandom,
+            403 => LinuxSyscall::ClockGettime,
+            n => LinuxSyscall::Unknown(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_syscall() {
+        assert_eq!(LinuxSyscall::from(93), LinuxSyscall::Exit);
+        assert_eq!(LinuxSyscall::from(64), LinuxSyscall::Write);
+        assert_eq!(LinuxSyscall::from(403), LinuxSyscall::ClockGettime);
+    }
+
+    #[test]
+    fn unknown_syscall() {
+        assert_eq!(LinuxSyscall::from(1234), LinuxSyscall::Unknown(1234));
+    }
+}
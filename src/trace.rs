@@ -0,0 +1,67 @@
+// Structured execution trace, one JSON object per line (JSON Lines):
+// {"pc":4,"inst":1081491,"mnemonic":"addi","rd":1,"value":1,"mem":null}
+// Fields that don't apply to an instruction are `null`.
+// Only built with the `serde` feature.
+use crate::cpu::MemEvent;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct TraceStep {
+    pub pc: u32,
+    #[serde(rename = "inst")]
+    pub raw_inst: u32,
+    // see `Inst::mnemonic`, never a pseudo-instruction
+    pub mnemonic: String,
+    // register written and its new value
+    pub rd: Option<usize>,
+    pub value: Option<u32>,
+    // last memory access of the instruction (the store for AMOs)
+    pub mem: Option<TraceAccess>,
+}
+
+// {"op":"load","addr":256,"value":7}
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TraceAccess {
+    Load { addr: u32, value: u32 },
+    Store { addr: u32, value: u32 },
+}
+
+impl TraceAccess {
+    // Fences don't access memory.
+    pub fn new(event: MemEvent) -> Option<Self> {
+        match event {
+            MemEvent::Load { addr, val, .. } => Some(TraceAccess::Load { addr, value: val }),
+            MemEvent::Store { addr, val, .. } => Some(TraceAccess::Store { addr, value: val }),
+            MemEvent::Fence { .. } => None,
+        }
+    }
+}
+
+impl TraceStep {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("trace steps are plain data")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_step() {
+        let step = TraceStep {
+            pc: 8,
+            raw_inst: 0x10502023,
+            mnemonic: "sw".to_string(),
+            rd: None,
+            value: None,
+            mem: TraceAccess::new(MemEvent::Store { pc: 8, addr: 0x100, val: 7 }),
+        };
+        assert_eq!(
+            step.to_json(),
+            r#"{"pc":8,"inst":273686563,"mnemonic":"sw","rd":null,"value":null,"mem":{"op":"store","addr":256,"value":7}}"#
+        );
+        assert!(TraceAccess::new(MemEvent::Fence { pc: 8 }).is_none());
+    }
+}